@@ -24,13 +24,12 @@ use once_cell::sync::Lazy;
 use serde_json::{json, Value};
 use subql_indexer_utils::{constants::decode_proxy_version, error::Error, types::Result};
 // use tdn::prelude::PeerKey;
-use libp2p::identity;
 use tokio::sync::RwLock;
 
 use crate::cli::COMMAND;
 use crate::metadata::auto_reduce_allocation_enabled;
 use crate::metrics::{get_services_version, get_status};
-use crate::mod_libp2p::{network::EventLoop, start_libp2p_process};
+use crate::mod_libp2p::{dht::publish_attestation, start_libp2p_process};
 // use crate::p2p::{start_network, stop_network};
 
 // sk = 0, address = 0x7e5f4552091a69125d5dfcb7b8c2659029395bdf
@@ -87,7 +86,7 @@ pub async fn handle_account(value: &Value) -> Result<()> {
         }
     });
 
-    let (controller_sk, controller, peer) = if let Some(sk) = fetch_controller {
+    let (controller, has_controller) = if let Some(sk) = fetch_controller {
         let sk = COMMAND.decrypt(sk).unwrap_or(
             "0x0000000000000000000000000000000000000000000000000000000000000001".to_string(),
         );
@@ -95,18 +94,13 @@ pub async fn handle_account(value: &Value) -> Result<()> {
         let controller = sk[2..]
             .parse::<LocalWallet>()
             .map_err(|_| Error::InvalidController(1038))?;
-        let peer = identity::secp256k1::SecretKey::try_from_bytes(
-            &mut hex::decode(&sk[2..]).map_err(|_| Error::InvalidController(1039))?,
-        )
-        .map_err(|_| Error::InvalidController(1039))?;
-        (sk[2..].to_string(), controller, Some(peer))
+        (controller, true)
     } else {
         (
-            "0000000000000000000000000000000000000000000000000000000000000001".to_string(),
             "0000000000000000000000000000000000000000000000000000000000000001"
                 .parse::<LocalWallet>()
                 .unwrap(),
-            None,
+            false,
         )
     };
     let new_c = controller.address();
@@ -120,20 +114,13 @@ pub async fn handle_account(value: &Value) -> Result<()> {
     *account = new_account;
     drop(account);
 
-    if old_c != new_c {
-        if let Some(_key) = peer {
-            info!("Need restart p2p network...");
-            tokio::spawn(async move {
-                EventLoop::stop().await;
-                if controller_sk
-                    != *"0000000000000000000000000000000000000000000000000000000000000001"
-                {
-                    start_libp2p_process(&controller_sk).await;
-                }
-                // stop_network().await;
-                // start_network(key).await;
-            });
-        }
+    if old_c != new_c && has_controller {
+        // the p2p identity is the node key, only attest the new controller
+        info!("Controller changed, publish p2p attestation...");
+        tokio::spawn(async move {
+            start_libp2p_process().await;
+            publish_attestation().await;
+        });
     }
 
     Ok(())
@@ -1,38 +1,345 @@
 use futures_util::{Stream, StreamExt};
-use reqwest_streams::*;
-use serde::{Deserialize, Serialize};
+use once_cell::sync::Lazy;
+use reqwest::header::CONTENT_TYPE;
 use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
 use subql_indexer_utils::{error::Error, payg::MultipleQueryState, types::Result};
 use tokenizers::tokenizer::Tokenizer;
 use tokio::sync::mpsc::{channel, Sender};
+use tokio::sync::RwLock;
 use tokio_stream::wrappers::ReceiverStream;
 
-use crate::payg::{before_query_multiple_state, post_query_multiple_state};
+use crate::ai_queue::acquire;
+use crate::cli::COMMAND;
+use crate::payg::{
+    before_query_multiple_state, check_multiple_state_afford, post_query_multiple_state,
+};
+use crate::primitives::{AI_RESERVED_OUTPUT_TOKENS, AI_TOKENIZER_MISS_MAX, AI_TOKENIZER_MISS_TIME};
+use crate::project::{AiPricing, Project};
 
 const SCALE: usize = 1;
+/// bill the output tokens every batch
 const BATCH: usize = 10;
 const BERT_BASE_MULTILINGUAL_UNCASED: &[u8] = include_bytes!("../tokenizer.json");
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
-struct Message {
-    role: String,
-    content: String,
+/// the bundled tokenizer, fallback when model's tokenizer missing
+static DEFAULT_TOKENIZER: Lazy<Option<Arc<Tokenizer>>> = Lazy::new(|| {
+    Tokenizer::from_bytes(BERT_BASE_MULTILINGUAL_UNCASED)
+        .ok()
+        .map(Arc::new)
+});
+
+/// the loaded tokenizers, key is model name
+static TOKENIZERS: Lazy<RwLock<HashMap<String, Arc<Tokenizer>>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// the models without tokenizer file, key is model name, value is the time of probed
+static TOKENIZER_MISSES: Lazy<RwLock<HashMap<String, Instant>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// the OpenAI compatible routes
+#[derive(Clone, Copy, Debug)]
+pub enum AiRoute {
+    ChatCompletions,
+    Completions,
+    Embeddings,
+    Models,
+}
+
+impl AiRoute {
+    pub fn from_path(path: &str) -> Result<AiRoute> {
+        match path.trim_matches('/') {
+            "chat/completions" => Ok(AiRoute::ChatCompletions),
+            "completions" => Ok(AiRoute::Completions),
+            "embeddings" => Ok(AiRoute::Embeddings),
+            "models" => Ok(AiRoute::Models),
+            _ => Err(Error::InvalidRequest(1075)),
+        }
+    }
+
+    fn path(&self) -> &'static str {
+        match self {
+            AiRoute::ChatCompletions => "chat/completions",
+            AiRoute::Completions => "completions",
+            AiRoute::Embeddings => "embeddings",
+            AiRoute::Models => "models",
+        }
+    }
+
+    /// the upstream url of route, the endpoint is the base url (http://localhost:11434)
+    /// or url of any route (http://localhost:11434/v1/chat/completions)
+    pub fn url(&self, endpoint: &str) -> String {
+        let base = match endpoint.find("/v1/") {
+            Some(i) => &endpoint[..i],
+            None => endpoint.trim_end_matches('/').trim_end_matches("/v1"),
+        };
+        format!("{}/v1/{}", base, self.path())
+    }
+
+    /// embeddings only bill the input tokens
+    fn bill_output(&self) -> bool {
+        !matches!(self, AiRoute::Embeddings | AiRoute::Models)
+    }
+}
+
+/// the shared client of AI backends
+static AI_CLIENT: Lazy<reqwest::Client> = Lazy::new(reqwest::Client::new);
+
+/// the AI project policy, checked before forwarding to remote
+#[derive(Clone, Debug, Default)]
+pub struct AiPolicy {
+    /// the allowed models, empty is all allowed
+    pub models: Vec<String>,
+    /// the max input tokens, 0 is unlimited
+    pub max_input_tokens: usize,
+    /// the max output tokens, 0 is unlimited
+    pub max_output_tokens: usize,
+    /// payg query must have max_tokens when output tokens unlimited
+    pub require_max_tokens: bool,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
-struct RequestMessage {
+impl AiPolicy {
+    /// allowed the model, or the model without tag (llama3:8b => llama3)
+    fn allowed(&self, model: &str) -> bool {
+        let name = model.split_once(':').map(|(n, _)| n).unwrap_or(model);
+        self.models.is_empty() || self.models.iter().any(|m| m == model || m == name)
+    }
+
+    /// check the request, and limit the output tokens when max_tokens missing.
+    /// returns the worst-case output tokens.
+    fn check(&self, route: AiRoute, request: &AiRequest, req: &mut Value) -> Result<Option<usize>> {
+        if !self.allowed(&request.model) {
+            return Err(Error::InvalidRequest(1077));
+        }
+
+        if self.max_input_tokens > 0 && request.input_tokens > self.max_input_tokens {
+            return Err(Error::InvalidRequest(1078));
+        }
+
+        if !route.bill_output() {
+            return Ok(Some(0));
+        }
+
+        let requested = ["max_tokens", "max_completion_tokens"]
+            .iter()
+            .filter_map(|k| req[*k].as_u64())
+            .min()
+            .map(|n| n as usize);
+        if self.max_output_tokens == 0 {
+            return Ok(requested);
+        }
+
+        match requested {
+            Some(n) if n > self.max_output_tokens => Err(Error::InvalidRequest(1079)),
+            Some(n) => Ok(Some(n)),
+            None => {
+                req["max_tokens"] = json!(self.max_output_tokens);
+                Ok(Some(self.max_output_tokens))
+            }
+        }
+    }
+
+    /// the output tokens reserved by payg query before querying, use the default
+    /// when unlimited, and the real output tokens are paid when responding.
+    fn reserved_output(&self, max_output: Option<usize>) -> Result<usize> {
+        match max_output {
+            Some(n) => Ok(n),
+            None if self.require_max_tokens => Err(Error::InvalidRequest(1085)),
+            None => Ok(AI_RESERVED_OUTPUT_TOKENS),
+        }
+    }
+}
+
+/// the parsed request of OpenAI compatible routes
+struct AiRequest {
     model: String,
     stream: bool,
-    messages: Vec<Message>,
+    input_tokens: usize,
+    tokenizer: Option<Arc<Tokenizer>>,
+}
+
+impl AiRequest {
+    async fn parse(route: AiRoute, req: &Value) -> Result<AiRequest> {
+        let model = req["model"]
+            .as_str()
+            .ok_or(Error::Serialize(1142))?
+            .to_owned();
+        let stream = req["stream"].as_bool().unwrap_or(false);
+        let tokenizer = tokenizer_load(&model).await.ok();
+
+        let input_tokens = match route {
+            AiRoute::ChatCompletions => {
+                let messages = req["messages"].as_array().ok_or(Error::Serialize(1142))?;
+                messages
+                    .iter()
+                    .map(|msg| value_tokens(&msg["content"], tokenizer.as_ref()))
+                    .sum::<usize>()
+            }
+            AiRoute::Completions if !req["prompt"].is_null() => {
+                value_tokens(&req["prompt"], tokenizer.as_ref())
+            }
+            AiRoute::Embeddings if !req["input"].is_null() => {
+                value_tokens(&req["input"], tokenizer.as_ref())
+            }
+            _ => return Err(Error::Serialize(1142)),
+        };
+
+        Ok(AiRequest {
+            model,
+            stream: stream && route.bill_output(),
+            input_tokens,
+            tokenizer,
+        })
+    }
+}
+
+/// the output tokens of response
+#[derive(Default)]
+struct OutputTokens {
+    /// tokenized from the content
+    counted: usize,
+    /// the completion tokens in usage block, prefer it when provided
+    usage: Option<usize>,
+    /// had billed tokens
+    billed: usize,
+}
+
+impl OutputTokens {
+    fn count(&mut self, msg: &Value, tokenizer: Option<&Arc<Tokenizer>>, is_stream: bool) {
+        if let Some(n) = msg
+            .pointer("/usage/completion_tokens")
+            .and_then(|v| v.as_u64())
+        {
+            self.usage = Some(n as usize);
+        }
+
+        for choice in msg["choices"].as_array().unwrap_or(&vec![]) {
+            let content = if is_stream {
+                &choice["delta"]["content"]
+            } else {
+                &choice["message"]["content"]
+            };
+            // legacy completions is text
+            let text = content.as_str().or(choice["text"].as_str()).unwrap_or("");
+            self.counted += count_tokens(text, tokenizer);
+        }
+    }
+
+    fn unbilled(&self) -> usize {
+        self.usage
+            .unwrap_or(self.counted)
+            .saturating_sub(self.billed)
+    }
 }
 
-pub fn _tokenize(value: &str) -> Result<usize> {
-    let tokenizer = tokenizer_load()?;
+pub async fn _tokenize(model: &str, value: &str) -> Result<usize> {
+    let tokenizer = tokenizer_load(model).await?;
     tokenize_with(value, &tokenizer, true)
 }
 
-fn tokenizer_load() -> Result<Tokenizer> {
-    Tokenizer::from_bytes(BERT_BASE_MULTILINGUAL_UNCASED).map_err(|_e| Error::AiTokenizer(1204))
+/// load the tokenizer of the model, and cache it.
+/// the missing models are cached with ttl, the model is from client request.
+async fn tokenizer_load(model: &str) -> Result<Arc<Tokenizer>> {
+    if let Some(tokenizer) = TOKENIZERS.read().await.get(model) {
+        return Ok(tokenizer.clone());
+    }
+    if tokenizer_missed(model).await {
+        return DEFAULT_TOKENIZER.clone().ok_or(Error::AiTokenizer(1204));
+    }
+
+    if let Some(path) = tokenizer_path(&COMMAND.ai_tokenizer_dir, model) {
+        match Tokenizer::from_file(&path) {
+            Ok(tokenizer) => {
+                let tokenizer = Arc::new(tokenizer);
+                TOKENIZERS
+                    .write()
+                    .await
+                    .insert(model.to_owned(), tokenizer.clone());
+                return Ok(tokenizer);
+            }
+            Err(err) => warn!("AI tokenizer {:?} invalid: {}", path, err),
+        }
+    }
+
+    tokenizer_miss(model).await;
+    DEFAULT_TOKENIZER.clone().ok_or(Error::AiTokenizer(1204))
+}
+
+/// the model probed without tokenizer recently
+async fn tokenizer_missed(model: &str) -> bool {
+    TOKENIZER_MISSES
+        .read()
+        .await
+        .get(model)
+        .map(|t| t.elapsed().as_secs() < AI_TOKENIZER_MISS_TIME)
+        .unwrap_or(false)
+}
+
+/// cache the missing model, drop the expired first, and not grow over the max
+async fn tokenizer_miss(model: &str) {
+    let mut misses = TOKENIZER_MISSES.write().await;
+    misses.retain(|_, t| t.elapsed().as_secs() < AI_TOKENIZER_MISS_TIME);
+    if misses.len() < AI_TOKENIZER_MISS_MAX {
+        misses.insert(model.to_owned(), Instant::now());
+    }
+}
+
+/// find the tokenizer file of model in the directory, supported:
+/// `{dir}/{model}/tokenizer.json` (e.g. meta-llama/Meta-Llama-3-8B),
+/// `{dir}/{model}.json`, and without the tag (e.g. llama3:8b => llama3).
+fn tokenizer_path(dir: &str, model: &str) -> Option<PathBuf> {
+    let dir = dir.trim();
+    let model = model.trim();
+    if dir.is_empty() || model.is_empty() || model.split('/').any(|p| p.is_empty() || p == "..") {
+        return None;
+    }
+
+    let mut names = vec![model];
+    if let Some((name, _tag)) = model.split_once(':') {
+        names.push(name);
+    }
+
+    let dir = Path::new(dir);
+    for name in names {
+        let nested = dir.join(name).join("tokenizer.json");
+        if nested.is_file() {
+            return Some(nested);
+        }
+        let single = dir.join(format!("{}.json", name));
+        if single.is_file() {
+            return Some(single);
+        }
+    }
+
+    None
+}
+
+/// the tokens of request input, supported text, text parts, array of texts and token ids
+fn value_tokens(value: &Value, tokenizer: Option<&Arc<Tokenizer>>) -> usize {
+    match value {
+        Value::String(s) => count_tokens(s, tokenizer),
+        Value::Number(_) => 1,
+        Value::Array(items) => items.iter().map(|v| value_tokens(v, tokenizer)).sum(),
+        Value::Object(part) => part
+            .get("text")
+            .and_then(|t| t.as_str())
+            .map(|t| count_tokens(t, tokenizer))
+            .unwrap_or(0),
+        _ => 0,
+    }
+}
+
+fn count_tokens(value: &str, tokenizer: Option<&Arc<Tokenizer>>) -> usize {
+    if value.is_empty() {
+        return 0;
+    }
+    match tokenizer {
+        Some(tokenizer) => tokenize_with(value, tokenizer, false).unwrap_or(value.len()),
+        None => value.len(),
+    }
 }
 
 fn tokenize_with(value: &str, tokenizer: &Tokenizer, is_scalar: bool) -> Result<usize> {
@@ -54,80 +361,218 @@ fn tokenize_with(value: &str, tokenizer: &Tokenizer, is_scalar: bool) -> Result<
     Ok(real_num)
 }
 
+/// parse and check the request by project policy,
+/// and check the channel can afford the worst-case cost before query
+async fn prepare_request(
+    route: AiRoute,
+    project: &Project,
+    req: &mut Value,
+    state: Option<&MultipleQueryState>,
+) -> Result<(AiRequest, AiPricing)> {
+    let request = AiRequest::parse(route, req).await?;
+    let policy = COMMAND.ai_policy(&project.id);
+    let max_output = policy.check(route, &request, req)?;
+
+    let pricing = project.ai_pricing(&request.model);
+    if let Some(state) = state {
+        let max_output = policy.reserved_output(max_output)?;
+        let units = pricing.units(request.input_tokens, max_output);
+        check_multiple_state_afford(state, units).await?;
+    }
+
+    Ok((request, pricing))
+}
+
+/// the backend of url, for queueing the requests
+fn backend(url: &str) -> &str {
+    url.find("/v1/").map(|i| &url[..i]).unwrap_or(url)
+}
+
+/// the consumer of request, for fair queueing
+fn consumer(state: Option<&MultipleQueryState>) -> String {
+    state
+        .map(|s| format!("{:#x}", s.channel_id))
+        .unwrap_or("whitelist".to_owned())
+}
+
+/// send the request to remote model
+async fn remote_request(url: &str, req: &Value) -> Result<reqwest::Response> {
+    let req_s = serde_json::to_string(req).unwrap_or("".to_owned());
+    AI_CLIENT
+        .post(url)
+        .header(CONTENT_TYPE, "application/json")
+        .body(req_s)
+        .send()
+        .await
+        .map_err(|_e| Error::AiModel(1206))
+}
+
+/// query the remote model and send the SSE events to client, no state is whitelist query
 pub async fn connect_remote(
-    endpoint: String,
+    url: String,
+    route: AiRoute,
     tx: Sender<String>,
-    req: Value,
-    state: MultipleQueryState,
-    is_test: bool,
+    mut req: Value,
+    project: Project,
+    state: Option<MultipleQueryState>,
 ) -> Result<()> {
-    let req_s = serde_json::to_string(&req).unwrap_or("".to_owned());
-    let request: RequestMessage =
-        serde_json::from_value(req).map_err(|_| Error::Serialize(1142))?;
+    let (request, pricing) = prepare_request(route, &project, &mut req, state.as_ref()).await?;
+    let tokenizer = request.tokenizer.as_ref();
 
-    let mut req_num = 0;
-    if let Ok(tokenizer) = tokenizer_load() {
-        for msg in request.messages {
-            req_num += tokenize_with(&msg.content, &tokenizer, false)?;
-        }
-    } else {
-        for msg in request.messages {
-            req_num += msg.content.len();
-        }
-    }
+    // wait the backend, not pay when timeout
+    let consumer = consumer(state.as_ref());
+    let _permit = acquire(backend(&url), &consumer, &project.id, Some(&tx)).await?;
 
     // pay by real count
-    if !is_test {
-        pay_by_token(req_num, &tx, state.clone(), true).await?;
+    if let Some(state) = &state {
+        let units = pricing.units(request.input_tokens, 0);
+        pay_by_token(units, &tx, state.clone(), true).await?;
     }
 
-    // open stream and send query to remote
+    // send query to remote
     // http://localhost:11434/v1/chat/completions
-    let client = reqwest::Client::new();
-    let mut stream = client
-        .post(endpoint)
-        .body(req_s)
-        .send()
-        .await
-        .map_err(|_e| Error::AiModel(1206))?
-        .json_array_stream::<Value>(1024);
+    let res = remote_request(&url, &req).await?;
+
+    let mut output = OutputTokens::default();
+    if request.stream {
+        // parse the SSE events, split by empty line
+        let mut stream = res.bytes_stream();
+        let mut buffer: Vec<u8> = vec![];
+        let mut done = false;
+        while let Some(Ok(chunk)) = stream.next().await {
+            buffer.extend(chunk.iter().filter(|b| **b != b'\r'));
+            while let Some(pos) = buffer.windows(2).position(|w| w == b"\n\n") {
+                let event: Vec<u8> = buffer.drain(..pos + 2).collect();
+                let data = match sse_data(&event) {
+                    Some(data) => data,
+                    None => continue,
+                };
+                if data == "[DONE]" {
+                    done = true;
+                    break;
+                }
+                let msg = match serde_json::from_str::<Value>(&data) {
+                    Ok(msg) => msg,
+                    Err(_) => continue,
+                };
+                output.count(&msg, tokenizer, true);
 
-    let mut count = 0;
-    while let Some(Ok(msg)) = stream.next().await {
-        count += 1;
+                // send to client
+                if tx.send(build_data(msg)).await.is_err() {
+                    done = true;
+                    break;
+                }
 
-        // pay by real count
-        if count == BATCH {
-            if !is_test {
-                pay_by_token(count, &tx, state.clone(), false).await?;
+                // pay by real count
+                let unbilled = output.unbilled();
+                if unbilled >= BATCH {
+                    if let Some(state) = &state {
+                        let units = pricing.units(0, unbilled);
+                        pay_by_token(units, &tx, state.clone(), false).await?;
+                    }
+                    output.billed += unbilled;
+                }
+            }
+            if done {
+                break;
             }
-            count = 0;
         }
-
-        // send to client
-        if tx.send(build_data(msg)).await.is_err() {
-            break;
+    } else {
+        let msg: Value = res.json().await.map_err(|_e| Error::AiModel(1207))?;
+        if route.bill_output() {
+            output.count(&msg, tokenizer, false);
         }
+        let _ = tx.send(build_data(msg)).await;
     }
 
-    if count != 0 && !is_test {
-        pay_by_token(count, &tx, state, true).await?;
+    let unbilled = output.unbilled();
+    if unbilled != 0 {
+        if let Some(state) = state {
+            pay_by_token(pricing.units(0, unbilled), &tx, state, true).await?;
+        }
     }
+    let _ = tx.send(build_done()).await;
 
     Ok(())
 }
 
+/// query the remote model without stream, returns the response and the latest state
+pub async fn api_query(
+    url: String,
+    route: AiRoute,
+    mut req: Value,
+    project: Project,
+    state: Option<MultipleQueryState>,
+) -> Result<(Value, Option<String>)> {
+    let (request, pricing) = prepare_request(route, &project, &mut req, state.as_ref()).await?;
+
+    // wait the backend, not pay when timeout
+    let consumer = consumer(state.as_ref());
+    let _permit = acquire(backend(&url), &consumer, &project.id, None).await?;
+
+    // pay the input before query
+    let mut latest = None;
+    if let Some(state) = &state {
+        let units = pricing.units(request.input_tokens, 0);
+        latest = Some(pay_units(units, state.clone()).await?.0);
+    }
+
+    let res = remote_request(&url, &req).await?;
+    let msg: Value = res.json().await.map_err(|_e| Error::AiModel(1207))?;
+
+    if route.bill_output() {
+        let mut output = OutputTokens::default();
+        output.count(&msg, request.tokenizer.as_ref(), false);
+        let unbilled = output.unbilled();
+        if unbilled != 0 {
+            if let Some(state) = state {
+                latest = Some(pay_units(pricing.units(0, unbilled), state).await?.0);
+            }
+        }
+    }
+
+    Ok((msg, latest.map(|s| s.to_bs64())))
+}
+
+/// list the models of remote, no billing
+pub async fn api_models(url: String) -> Result<Value> {
+    AI_CLIENT
+        .get(url)
+        .send()
+        .await
+        .map_err(|_e| Error::AiModel(1206))?
+        .json()
+        .await
+        .map_err(|_e| Error::AiModel(1207))
+}
+
+/// the data of SSE event, multiple data lines joined by newline
+fn sse_data(event: &[u8]) -> Option<String> {
+    let event = String::from_utf8_lossy(event);
+    let lines: Vec<&str> = event
+        .lines()
+        .filter_map(|line| line.strip_prefix("data:"))
+        .map(|line| line.trim())
+        .collect();
+    if lines.is_empty() {
+        None
+    } else {
+        Some(lines.join("\n"))
+    }
+}
+
 pub fn api_stream(
-    endpoint: String,
+    url: String,
+    route: AiRoute,
     req: Value,
-    state: MultipleQueryState,
-    is_test: bool,
+    project: Project,
+    state: Option<MultipleQueryState>,
 ) -> impl Stream<Item = String> {
     let (tx, rx) = channel::<String>(1024);
 
     tokio::spawn(async move {
         let tx1 = tx.clone();
-        if let Err(err) = connect_remote(endpoint, tx1, req, state, is_test).await {
+        if let Err(err) = connect_remote(url, route, tx1, req, project, state).await {
             let state = build_data(err.to_json());
             let _ = tx.send(state).await;
         }
@@ -137,21 +582,23 @@ pub fn api_stream(
     ReceiverStream::new(rx).boxed()
 }
 
-fn build_data(raw: Value) -> String {
+pub fn build_data(raw: Value) -> String {
     let s = serde_json::to_string(&raw).unwrap_or("".to_owned());
     format!("data: {} \n\n", s)
 }
 
+fn build_done() -> String {
+    "data: [DONE] \n\n".to_owned()
+}
+
+/// pay the channel units, converted from tokens by the AI pricing
 async fn pay_by_token(
-    num: usize,
+    units: u64,
     tx: &Sender<String>,
     state: MultipleQueryState,
     must_send: bool,
 ) -> Result<()> {
-    let real_num = if num > SCALE { num / SCALE } else { 1 };
-
-    let (state, keyname, state_cache, inactive) =
-        before_query_multiple_state(state, real_num as u64).await?;
+    let (state, inactive) = pay_units(units, state).await?;
 
     if must_send || inactive {
         let state = build_data(json!({
@@ -160,7 +607,68 @@ async fn pay_by_token(
         let _ = tx.send(state).await;
     }
 
+    Ok(())
+}
+
+async fn pay_units(units: u64, state: MultipleQueryState) -> Result<(MultipleQueryState, bool)> {
+    let real_num = if units > SCALE as u64 {
+        units / SCALE as u64
+    } else {
+        1
+    };
+
+    let (state, keyname, state_cache, inactive) =
+        before_query_multiple_state(state, real_num).await?;
+
     post_query_multiple_state(keyname, state_cache).await;
 
-    Ok(())
+    Ok((state, inactive))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn tokenizer_miss_cached() {
+        let model = "unknown-model:1b";
+        assert!(!tokenizer_missed(model).await);
+
+        // fallback to the bundled tokenizer, and not probe the files again
+        assert!(tokenizer_load(model).await.is_ok());
+        assert!(tokenizer_missed(model).await);
+        assert!(tokenizer_load(model).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn reserve_default_output() {
+        let mut req = json!({
+            "model": "llama3:8b",
+            "messages": [{ "role": "user", "content": "hello" }],
+        });
+        let request = AiRequest::parse(AiRoute::ChatCompletions, &req)
+            .await
+            .unwrap();
+
+        // default config: unlimited output, reserve the default without max_tokens
+        let mut policy = COMMAND.ai_policy("QmAi");
+        let max_output = policy
+            .check(AiRoute::ChatCompletions, &request, &mut req)
+            .unwrap();
+        assert_eq!(max_output, None);
+        assert!(req["max_tokens"].is_null());
+        assert_eq!(
+            policy.reserved_output(max_output).unwrap(),
+            AI_RESERVED_OUTPUT_TOKENS
+        );
+        assert_eq!(policy.reserved_output(Some(16)).unwrap(), 16);
+
+        // strict policy opted by operator
+        policy.require_max_tokens = true;
+        assert!(matches!(
+            policy.reserved_output(max_output),
+            Err(Error::InvalidRequest(1085))
+        ));
+        assert_eq!(policy.reserved_output(Some(16)).unwrap(), 16);
+    }
 }
@@ -0,0 +1,258 @@
+// This file is part of SubQuery.
+
+// Copyright (C) 2020-2024 SubQuery Pte Ltd authors & contributors
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use once_cell::sync::Lazy;
+use serde_json::json;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use subql_indexer_utils::{error::Error, types::Result};
+use tokio::sync::{mpsc::Sender, oneshot};
+
+use crate::ai::build_data;
+use crate::cli::COMMAND;
+use crate::metrics::{add_metrics_ai_wait, update_metrics_ai_queue};
+use crate::primitives::AI_QUEUE_NOTIFY_TIME;
+
+/// the queues of AI backends, key is the backend base url
+static QUEUES: Lazy<Mutex<HashMap<String, Queue>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+static WAITER_ID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Default)]
+struct Queue {
+    /// the running requests
+    running: usize,
+    /// the consumers with waiters, in round-robin order
+    order: VecDeque<String>,
+    /// the waiters of consumers
+    waiters: HashMap<String, VecDeque<(u64, oneshot::Sender<()>)>>,
+}
+
+impl Queue {
+    fn waiting(&self) -> usize {
+        self.waiters.values().map(|w| w.len()).sum()
+    }
+
+    /// the position in round-robin order, 1 is the next
+    fn position(&self, consumer: &str, id: u64) -> Option<usize> {
+        let index = self
+            .waiters
+            .get(consumer)?
+            .iter()
+            .position(|(w, _)| *w == id)?;
+        let mine = self.order.iter().position(|c| c == consumer)?;
+
+        let mut ahead = 0;
+        for (o, c) in self.order.iter().enumerate() {
+            let rounds = if o < mine { index + 1 } else { index };
+            ahead += self
+                .waiters
+                .get(c)
+                .map(|w| w.len())
+                .unwrap_or(0)
+                .min(rounds);
+        }
+        Some(ahead + 1)
+    }
+
+    fn remove(&mut self, consumer: &str, id: u64) -> bool {
+        if let Some(waiters) = self.waiters.get_mut(consumer) {
+            if let Some(i) = waiters.iter().position(|(w, _)| *w == id) {
+                waiters.remove(i);
+                if waiters.is_empty() {
+                    self.waiters.remove(consumer);
+                    self.order.retain(|c| c != consumer);
+                }
+                return true;
+            }
+        }
+        false
+    }
+
+    /// hand over the slot to next waiter, round-robin by consumers
+    fn next(&mut self) -> bool {
+        while let Some(consumer) = self.order.pop_front() {
+            let (waiter, empty) = match self.waiters.get_mut(&consumer) {
+                Some(waiters) => (waiters.pop_front(), waiters.is_empty()),
+                None => (None, true),
+            };
+            if empty {
+                self.waiters.remove(&consumer);
+            } else {
+                self.order.push_back(consumer);
+            }
+
+            if let Some((_, sender)) = waiter {
+                if sender.send(()).is_ok() {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+/// release the slot of backend
+fn release(backend: &str) {
+    let mut queues = QUEUES.lock().unwrap();
+    if let Some(queue) = queues.get_mut(backend) {
+        if !queue.next() {
+            queue.running = queue.running.saturating_sub(1);
+        }
+        if queue.running == 0 && queue.order.is_empty() {
+            queues.remove(backend);
+        }
+    }
+}
+
+/// The running slot of AI backend, release when drop
+pub struct AiPermit {
+    backend: Option<String>,
+}
+
+impl Drop for AiPermit {
+    fn drop(&mut self) {
+        if let Some(backend) = &self.backend {
+            release(backend);
+        }
+    }
+}
+
+/// The waiter in queue, remove from queue when drop (timeout or cancelled)
+struct Waiter {
+    backend: String,
+    consumer: String,
+    deployment: String,
+    id: u64,
+    granted: bool,
+}
+
+impl Waiter {
+    fn position(&self) -> Option<usize> {
+        let queues = QUEUES.lock().unwrap();
+        queues.get(&self.backend)?.position(&self.consumer, self.id)
+    }
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        tokio::spawn(update_metrics_ai_queue(self.deployment.clone(), false));
+        if self.granted {
+            return;
+        }
+
+        let removed = {
+            let mut queues = QUEUES.lock().unwrap();
+            queues
+                .get_mut(&self.backend)
+                .map(|q| q.remove(&self.consumer, self.id))
+                .unwrap_or(false)
+        };
+        // had been handed over the slot, but not taken
+        if !removed {
+            release(&self.backend);
+        }
+    }
+}
+
+/// wait the running slot of AI backend, fair between consumers (channels).
+/// the queue position will send to the SSE stream when notify.
+pub async fn acquire(
+    backend: &str,
+    consumer: &str,
+    deployment: &str,
+    notify: Option<&Sender<String>>,
+) -> Result<AiPermit> {
+    let limit = COMMAND.ai_max_concurrent;
+    if limit == 0 {
+        return Ok(AiPermit { backend: None });
+    }
+
+    let id = WAITER_ID.fetch_add(1, Ordering::Relaxed);
+    let mut receiver = {
+        let mut queues = QUEUES.lock().unwrap();
+        let queue = queues.entry(backend.to_owned()).or_default();
+        if queue.running < limit && queue.order.is_empty() {
+            queue.running += 1;
+            return Ok(AiPermit {
+                backend: Some(backend.to_owned()),
+            });
+        }
+
+        if COMMAND.ai_queue_size > 0 && queue.waiting() >= COMMAND.ai_queue_size {
+            return Err(Error::RateLimit(1081));
+        }
+        let per_consumer = queue.waiters.get(consumer).map(|w| w.len()).unwrap_or(0);
+        if COMMAND.ai_queue_per_consumer > 0 && per_consumer >= COMMAND.ai_queue_per_consumer {
+            return Err(Error::RateLimit(1082));
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        queue
+            .waiters
+            .entry(consumer.to_owned())
+            .or_default()
+            .push_back((id, sender));
+        if !queue.order.iter().any(|c| c == consumer) {
+            queue.order.push_back(consumer.to_owned());
+        }
+        receiver
+    };
+
+    update_metrics_ai_queue(deployment.to_owned(), true).await;
+    let mut waiter = Waiter {
+        backend: backend.to_owned(),
+        consumer: consumer.to_owned(),
+        deployment: deployment.to_owned(),
+        id,
+        granted: false,
+    };
+
+    let start = Instant::now();
+    let timeout = Duration::from_secs(COMMAND.ai_queue_timeout);
+    let mut last_position = 0;
+    let result = loop {
+        if let Some(tx) = notify {
+            if let Some(position) = waiter.position() {
+                if position != last_position {
+                    last_position = position;
+                    let event = build_data(json!({ "queue": { "position": position } }));
+                    let _ = tx.send(event).await;
+                }
+            }
+        }
+
+        tokio::select! {
+            res = &mut receiver => break res.map_err(|_| Error::RateLimit(1083)),
+            _ = tokio::time::sleep(Duration::from_secs(AI_QUEUE_NOTIFY_TIME)) => {
+                if !timeout.is_zero() && start.elapsed() >= timeout {
+                    break Err(Error::RateLimit(1083));
+                }
+            }
+        }
+    };
+    add_metrics_ai_wait(deployment.to_owned(), start.elapsed().as_millis() as u64).await;
+
+    result?;
+    waiter.granted = true;
+    Ok(AiPermit {
+        backend: Some(backend.to_owned()),
+    })
+}
@@ -115,8 +115,10 @@ pub async fn create_jwt(
     .map_err(|_| Error::AuthCreate(1003))
 }
 
+/// the deployment and authorization of close agreement,
+/// the compute units of query are charged when query, see `verify_auth`
 #[derive(Debug, PartialEq, Eq, Clone)]
-pub struct AuthQuery(pub String);
+pub struct AuthQuery(pub String, pub String);
 
 #[async_trait]
 impl<S> FromRequestParts<S> for AuthQuery
@@ -130,12 +132,12 @@ where
         _state: &S,
     ) -> std::result::Result<Self, Self::Rejection> {
         if !COMMAND.auth() {
-            return Ok(AuthQuery("".to_string()));
+            return Ok(AuthQuery("".to_string(), "".to_string()));
         }
 
         let authorisation = extract_auth_from_req(req)?;
-        let deployment_id = verify_auth(&authorisation).await?;
-        Ok(AuthQuery(deployment_id))
+        let deployment_id = jwt_deployment(&authorisation)?;
+        Ok(AuthQuery(deployment_id, authorisation))
     }
 }
 
@@ -154,20 +156,14 @@ where
         _state: &S,
     ) -> std::result::Result<Self, Self::Rejection> {
         let authorisation = extract_auth_from_req(req)?;
-        let claims = check_jwt(&authorisation)?;
-
-        if let Some(agreement) = claims.agreement {
-            let (daily_limit, daily_times, rate_limit, rate_times) =
-                get_agreement_limit(&agreement).await;
-            Ok(AuthQueryLimit(
-                daily_limit,
-                daily_times,
-                rate_limit,
-                rate_times,
-            ))
-        } else {
-            Ok(AuthQueryLimit(1, 0, 1, 0))
-        }
+        let (daily_limit, daily_times, rate_limit, rate_times) =
+            verify_auth_limit(&authorisation).await?;
+        Ok(AuthQueryLimit(
+            daily_limit,
+            daily_times,
+            rate_limit,
+            rate_times,
+        ))
     }
 }
 
@@ -182,22 +178,34 @@ fn extract_auth_from_req(req: &mut Parts) -> Result<String> {
     Ok(authorisation.to_string())
 }
 
-pub async fn verify_auth(authorisation: &str) -> Result<String> {
+/// verify every query (http, websocket & p2p), and consume the compute units of agreement
+pub async fn verify_auth(authorisation: &str, units: u64) -> Result<String> {
     let claims = check_jwt(authorisation)?;
     if let Some(agreement) = claims.agreement {
-        check_agreement_limit(&agreement).await?;
+        check_agreement_limit(&agreement, units).await?;
     }
 
     Ok(claims.deployment_id)
 }
 
-pub async fn verify_auth_ws(authorisation: &str) -> Result<String> {
+/// the limit and used times of agreement: daily limit, daily used, rate limit, rate used
+pub async fn verify_auth_limit(authorisation: &str) -> Result<(u64, u64, u64, u64)> {
     let claims = check_jwt(authorisation)?;
     if let Some(agreement) = claims.agreement {
-        check_agreement_daily_limit(&agreement).await?;
+        Ok(get_agreement_limit(&agreement).await)
+    } else {
+        Ok((1, 0, 1, 0))
     }
+}
 
-    Ok(claims.deployment_id)
+/// the deployment of the jwt token, not consume the limit
+pub fn jwt_deployment(authorisation: &str) -> Result<String> {
+    Ok(check_jwt(authorisation)?.deployment_id)
+}
+
+/// the agreement of the jwt token
+pub fn jwt_agreement(authorisation: &str) -> Option<String> {
+    check_jwt(authorisation).ok().and_then(|c| c.agreement)
 }
 
 fn check_jwt(auth: &str) -> Result<Claims> {
@@ -307,35 +315,13 @@ async fn save_agreement(agreement: &str, daily: u64, rate: u64, signer: Option<&
     }
 }
 
-async fn check_agreement_daily_limit(agreement: &str) -> Result<()> {
-    // check limit
-    let (daily_limit, daily_times, _, _) = get_agreement_limit(agreement).await;
-
-    if daily_times + 1 > daily_limit {
-        return Err(Error::DailyLimit(1051));
-    }
-
-    let mut conn = redis();
-
-    let (date, _) = day_and_second();
-    let daily_key = format!("{}-daily-{}", agreement, date);
-
-    let _: result::Result<(), ()> = redis::cmd("SETEX")
-        .arg(&daily_key)
-        .arg(86400)
-        .arg(daily_times + 1)
-        .query_async(&mut conn)
-        .await
-        .map_err(|err| error!("Redis 4 {}", err));
-
-    Ok(())
-}
-
-async fn check_agreement_limit(agreement: &str) -> Result<()> {
+/// the compute units consume the daily quota, and every query is 1 in the rate limit,
+/// so the method which units greater than rate limit still can be served
+async fn check_agreement_limit(agreement: &str, units: u64) -> Result<()> {
     // check limit
     let (daily_limit, daily_times, rate_limit, rate_times) = get_agreement_limit(agreement).await;
 
-    if daily_times + 1 > daily_limit {
+    if daily_times + units > daily_limit {
         return Err(Error::DailyLimit(1051));
     }
 
@@ -352,7 +338,7 @@ async fn check_agreement_limit(agreement: &str) -> Result<()> {
     let _: result::Result<(), ()> = redis::cmd("SETEX")
         .arg(&daily_key)
         .arg(86400)
-        .arg(daily_times + 1)
+        .arg(daily_times + units)
         .query_async(&mut conn)
         .await
         .map_err(|err| error!("Redis 4 {}", err));
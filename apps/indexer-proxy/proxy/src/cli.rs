@@ -23,20 +23,24 @@ use aes_gcm::{
 use digest::{generic_array::GenericArray, Digest};
 use once_cell::sync::Lazy;
 use redis::aio::MultiplexedConnection;
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 use subql_contracts::Network;
 use subql_indexer_utils::{
     constants::{
         BOOTNODE_DEFAULT_QUIC_ADDRESS, BOOTNODE_DEFAULT_TCP_ADDRESS, METRICS_DEFAULT_QUIC_ADDRESS,
-        METRICS_DEFAULT_TCP_ADDRESS, TEST_BOOTNODE_DEFAULT_QUIC_ADDRESS,
+        METRICS_DEFAULT_TCP_ADDRESS, METRICS_PEER_ID, PRODUCTION_BOOSTNODE_PEER_ID_LIST,
+        TEST_BOOSTNODE_PEER_ID_LIST, TEST_BOOTNODE_DEFAULT_QUIC_ADDRESS,
         TEST_BOOTNODE_DEFAULT_TCP_ADDRESS, TEST_METRICS_DEFAULT_QUIC_ADDRESS,
-        TEST_METRICS_DEFAULT_TCP_ADDRESS,
+        TEST_METRICS_DEFAULT_TCP_ADDRESS, TEST_METRICS_PEER_ID,
     },
     error::Error,
 };
 // use tdn::prelude::PeerId;
 use tokio::sync::OnceCell;
 
+use crate::ai::AiPolicy;
+
 const DEFAULT_TCP_ADDRESS: &str = "/ip4/0.0.0.0/tcp/7370";
 const DEFAULT_UDP_ADDRESS: &str = "/ip4/0.0.0.0/udp/7370/quic-v1";
 
@@ -56,8 +60,14 @@ pub async fn init_redis() {
         .unwrap();
 }
 
+#[cfg(not(test))]
 pub static COMMAND: Lazy<CommandLineArgs> = Lazy::new(CommandLineArgs::from_args);
 
+/// the tests run with the default command line
+#[cfg(test)]
+pub static COMMAND: Lazy<CommandLineArgs> =
+    Lazy::new(|| CommandLineArgs::from_iter(["subql-indexer-proxy"]));
+
 #[derive(Debug, StructOpt)]
 #[structopt(
     name = "Indexer Proxy",
@@ -100,15 +110,101 @@ pub struct CommandLineArgs {
     /// Bootstrap seeds for p2p network with MultiAddr style
     #[structopt(long = "bootstrap")]
     pub bootstrap: Vec<String>,
+    /// Only use the `--bootstrap` seeds, not append the SubQuery bootnodes and metrics node
+    #[structopt(long = "bootstrap-only")]
+    pub bootstrap_only: bool,
+    /// Replace the SubQuery bootnode peer ids, e.g. the local bootnode in tests.
+    /// Implies `--bootstrap-only`, the local nodes are dialed by `--bootstrap`
+    #[structopt(long = "bootnode-peer-id")]
+    pub bootnode_peer_id: Vec<String>,
+    /// Replace the SubQuery metrics node peer ids, e.g. the local metrics stand-in in tests.
+    /// Implies `--bootstrap-only`, the local nodes are dialed by `--bootstrap`
+    #[structopt(long = "metrics-peer-id")]
+    pub metrics_peer_id: Vec<String>,
+    /// Disable the p2p network, the swarm will not start and telemetry will not be sent
+    #[structopt(long = "no-p2p")]
+    pub no_p2p: bool,
+    /// The p2p node key (secp256k1 secret key hex), default is the key file
+    #[structopt(long = "p2p-key", default_value = "")]
+    pub p2p_key: String,
+    /// The p2p node key file, will generate when missing, keep the peer id stable.
+    /// The relative path is under the data dir
+    #[structopt(long = "p2p-key-file", default_value = "p2p.key")]
+    pub p2p_key_file: String,
+    /// The directory of persistent data (e.g. p2p key), mount it when running in docker
+    #[structopt(long = "data-dir", default_value = "data")]
+    pub data_dir: String,
     /// Open telemetry for SubQuery
     #[structopt(long = "telemetry", parse(try_from_str), default_value = "true")]
     pub telemetry: bool,
+    /// The max telemetry events buffered when metrics node is unreachable, the oldest will be dropped.
+    #[structopt(long = "telemetry-outbox-size", default_value = "1000")]
+    pub telemetry_outbox_size: u64,
     /// The auth bearer for prometheus fetch metrics
     #[structopt(long = "metrics-token", default_value = "thisismyAuthtoken")]
     pub metrics_token: String,
     /// The max overflow when unit greater than overflow configure.
     #[structopt(long = "max-unit-overflow", default_value = "10")]
     pub max_unit_overflow: u64,
+    /// The max lag blocks (targetHeight - lastHeight) allowed for paid queries, 0 is disabled.
+    #[structopt(long = "max-lag", default_value = "0")]
+    pub max_lag: u64,
+    /// The max lag blocks of special project, e.g. `--lag-threshold Qm...955X=1000`
+    #[structopt(long = "lag-threshold")]
+    pub lag_threshold: Vec<String>,
+    /// The action when project lag exceeds threshold: reject, or deprioritize
+    /// (limited concurrency lane, rejected when waiting too long)
+    #[structopt(long = "lag-policy", default_value = "reject")]
+    pub lag_policy: String,
+    /// The max concurrent websockets per auth token, 0 is unlimited.
+    #[structopt(long = "ws-max-per-token", default_value = "0")]
+    pub ws_max_per_token: usize,
+    /// The max concurrent websockets per close agreement, 0 is unlimited.
+    #[structopt(long = "ws-max-per-agreement", default_value = "0")]
+    pub ws_max_per_agreement: usize,
+    /// The max concurrent websockets per payg state channel, 0 is unlimited.
+    #[structopt(long = "ws-max-per-channel", default_value = "0")]
+    pub ws_max_per_channel: usize,
+    /// The max concurrent websockets per ip, 0 is unlimited.
+    #[structopt(long = "ws-max-per-ip", default_value = "0")]
+    pub ws_max_per_ip: usize,
+    /// Close the websocket when no message in the time (seconds), 0 is disabled.
+    #[structopt(long = "ws-idle-timeout", default_value = "0")]
+    pub ws_idle_timeout: u64,
+    /// The max lifetime of websocket (seconds), 0 is unlimited.
+    #[structopt(long = "ws-max-lifetime", default_value = "0")]
+    pub ws_max_lifetime: u64,
+    /// Reconnect the remote websocket when closed, and replay the subscriptions.
+    #[structopt(long = "ws-reconnect")]
+    pub ws_reconnect: bool,
+    /// The directory of AI tokenizers, e.g. `{dir}/{model}/tokenizer.json` or `{dir}/{model}.json`
+    #[structopt(long = "ai-tokenizer-dir", default_value = "")]
+    pub ai_tokenizer_dir: String,
+    /// The max input tokens of AI request, 0 is unlimited.
+    #[structopt(long = "ai-max-input-tokens", default_value = "0")]
+    pub ai_max_input_tokens: usize,
+    /// The max output tokens (max_tokens) of AI request, 0 is unlimited.
+    #[structopt(long = "ai-max-output-tokens", default_value = "0")]
+    pub ai_max_output_tokens: usize,
+    /// Reject the payg AI request without max_tokens when output tokens unlimited,
+    /// default reserve the channel balance of 1024 output tokens and pay the real output.
+    #[structopt(long = "ai-require-max-tokens")]
+    pub ai_require_max_tokens: bool,
+    /// The AI policy of special project, e.g. `--ai-policy Qm...955X=models:llama3|qwen2,max-input:8192,max-output:2048`
+    #[structopt(long = "ai-policy")]
+    pub ai_policy: Vec<String>,
+    /// The max concurrent requests per AI backend, 0 is unlimited.
+    #[structopt(long = "ai-max-concurrent", default_value = "0")]
+    pub ai_max_concurrent: usize,
+    /// The max waiting requests in queue per AI backend, 0 is unlimited.
+    #[structopt(long = "ai-queue-size", default_value = "100")]
+    pub ai_queue_size: usize,
+    /// The max waiting requests in queue per consumer channel, 0 is unlimited.
+    #[structopt(long = "ai-queue-per-consumer", default_value = "4")]
+    pub ai_queue_per_consumer: usize,
+    /// The max waiting time (seconds) in queue, 0 is unlimited.
+    #[structopt(long = "ai-queue-timeout", default_value = "60")]
+    pub ai_queue_timeout: u64,
 }
 
 impl CommandLineArgs {
@@ -192,12 +288,72 @@ impl CommandLineArgs {
         Network::from_str(&self.network)
     }
 
+    pub fn lag_threshold(&self, deployment: &str) -> u64 {
+        for item in &self.lag_threshold {
+            if let Some((project, blocks)) = item.split_once('=') {
+                if project.trim() == deployment {
+                    return blocks.trim().parse().unwrap_or(self.max_lag);
+                }
+            }
+        }
+        self.max_lag
+    }
+
+    pub fn lag_deprioritize(&self) -> bool {
+        self.lag_policy.trim().to_lowercase() == "deprioritize"
+    }
+
+    pub fn ai_policy(&self, deployment: &str) -> AiPolicy {
+        let mut policy = AiPolicy {
+            models: vec![],
+            max_input_tokens: self.ai_max_input_tokens,
+            max_output_tokens: self.ai_max_output_tokens,
+            require_max_tokens: self.ai_require_max_tokens,
+        };
+        for item in &self.ai_policy {
+            if let Some((project, rules)) = item.split_once('=') {
+                if project.trim() != deployment {
+                    continue;
+                }
+                for rule in rules.split(',') {
+                    match rule.trim().split_once(':') {
+                        Some(("models", models)) => {
+                            policy.models = models
+                                .split('|')
+                                .map(|m| m.trim().to_owned())
+                                .filter(|m| !m.is_empty())
+                                .collect();
+                        }
+                        Some(("max-input", n)) => {
+                            policy.max_input_tokens =
+                                n.trim().parse().unwrap_or(self.ai_max_input_tokens);
+                        }
+                        Some(("max-output", n)) => {
+                            policy.max_output_tokens =
+                                n.trim().parse().unwrap_or(self.ai_max_output_tokens);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+        policy
+    }
+
     pub fn redis_endpoint(&self) -> &str {
         &self.redis_endpoint
     }
 
+    /// the replaced peer ids are not the SubQuery nodes, not dial the SubQuery seeds
+    pub fn bootstrap_only(&self) -> bool {
+        self.bootstrap_only || !self.bootnode_peer_id.is_empty() || !self.metrics_peer_id.is_empty()
+    }
+
     pub fn bootstrap(&self) -> Vec<String> {
         let mut seeds = self.bootstrap.clone();
+        if self.bootstrap_only() {
+            return seeds;
+        }
         match self.network() {
             Network::Mainnet => {
                 seeds.push(METRICS_DEFAULT_QUIC_ADDRESS.to_string());
@@ -215,6 +371,45 @@ impl CommandLineArgs {
         seeds
     }
 
+    pub fn bootnode_peer_ids(&self) -> Vec<String> {
+        if !self.bootnode_peer_id.is_empty() {
+            return self.bootnode_peer_id.clone();
+        }
+        let peers: &[&str] = match self.network() {
+            Network::Mainnet => &PRODUCTION_BOOSTNODE_PEER_ID_LIST,
+            Network::Testnet => &TEST_BOOSTNODE_PEER_ID_LIST,
+            _ => &[],
+        };
+        peers.iter().map(|p| p.to_string()).collect()
+    }
+
+    pub fn metrics_peer_ids(&self) -> Vec<String> {
+        if !self.metrics_peer_id.is_empty() {
+            return self.metrics_peer_id.clone();
+        }
+        match self.network() {
+            Network::Mainnet => vec![METRICS_PEER_ID.to_string()],
+            Network::Testnet => vec![TEST_METRICS_PEER_ID.to_string()],
+            _ => vec![],
+        }
+    }
+
+    /// the absolute path of p2p key file, the relative path is under the data dir
+    pub fn p2p_key_path(&self) -> PathBuf {
+        let path = Path::new(&self.data_dir).join(&self.p2p_key_file);
+        if path.is_absolute() {
+            return path;
+        }
+        std::env::current_dir()
+            .map(|dir| dir.join(&path))
+            .unwrap_or(path)
+    }
+
+    /// send the telemetry to metrics node
+    pub fn telemetry(&self) -> bool {
+        self.telemetry && !self.no_p2p
+    }
+
     // pub fn telemetries(&self) -> Vec<PeerId> {
     //     if self.telemetry {
     //         match self.network() {
@@ -236,3 +431,77 @@ impl CommandLineArgs {
     //     }
     // }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn p2p_key_under_data_dir() {
+        let args = CommandLineArgs::from_iter(["subql-indexer-proxy", "--data-dir", "/subql/data"]);
+        assert_eq!(args.p2p_key_path(), PathBuf::from("/subql/data/p2p.key"));
+
+        // the relative data dir is resolved to absolute
+        let args = CommandLineArgs::from_iter(["subql-indexer-proxy"]);
+        let path = args.p2p_key_path();
+        assert!(path.is_absolute());
+        assert!(path.ends_with("data/p2p.key"));
+
+        // the absolute key file is not under the data dir
+        let args =
+            CommandLineArgs::from_iter(["subql-indexer-proxy", "--p2p-key-file", "/keys/p2p.key"]);
+        assert_eq!(args.p2p_key_path(), PathBuf::from("/keys/p2p.key"));
+    }
+
+    #[test]
+    fn peer_id_overrides() {
+        let args = CommandLineArgs::from_iter([
+            "subql-indexer-proxy",
+            "--network",
+            "testnet",
+            "--bootnode-peer-id",
+            "bootnode-a",
+            "--bootnode-peer-id",
+            "bootnode-b",
+            "--metrics-peer-id",
+            "metrics-a",
+            "--bootstrap",
+            "/ip4/127.0.0.1/tcp/7370",
+        ]);
+        assert_eq!(args.bootnode_peer_ids(), vec!["bootnode-a", "bootnode-b"]);
+        assert_eq!(args.metrics_peer_ids(), vec!["metrics-a"]);
+        // the overrides only dial the local seeds
+        assert!(args.bootstrap_only());
+        assert_eq!(args.bootstrap(), vec!["/ip4/127.0.0.1/tcp/7370"]);
+
+        let args = CommandLineArgs::from_iter([
+            "subql-indexer-proxy",
+            "--network",
+            "testnet",
+            "--metrics-peer-id",
+            "metrics-a",
+        ]);
+        assert!(args.bootstrap_only());
+        assert!(args.bootstrap().is_empty());
+
+        // fallback to the SubQuery nodes of network
+        let args = CommandLineArgs::from_iter(["subql-indexer-proxy", "--network", "testnet"]);
+        assert!(!args.bootstrap_only());
+        assert_eq!(args.bootstrap().len(), 4);
+        assert_eq!(
+            args.bootnode_peer_ids(),
+            TEST_BOOSTNODE_PEER_ID_LIST
+                .iter()
+                .map(|p| p.to_string())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(args.metrics_peer_ids(), vec![TEST_METRICS_PEER_ID]);
+    }
+
+    #[test]
+    fn ws_limits_disabled_by_default() {
+        let args = CommandLineArgs::from_iter(["subql-indexer-proxy"]);
+        assert_eq!(args.ws_idle_timeout, 0);
+        assert_eq!(args.ws_max_lifetime, 0);
+    }
+}
@@ -24,8 +24,8 @@ use ethers::{
 use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 use subql_contracts::{
-    consumer_host, consumer_host_parse, consumer_registry, l2_sqtoken_parse, plan_manager,
-    service_agreement_registry,
+    consumer_host, consumer_host_parse, consumer_registry, indexer_registry, l2_sqtoken_parse,
+    plan_manager, service_agreement_registry,
 };
 use subql_indexer_utils::{error::Error, price_oracle::convert_price};
 // use tdn::prelude::PeerId;
@@ -222,6 +222,24 @@ pub async fn check_consumer_controller(consumer: Address, signer: Address) -> Re
     Ok(is_controller)
 }
 
+pub async fn get_indexer_controller(indexer: Address) -> Result<Address, Error> {
+    let client = Arc::new(
+        Provider::<Http>::try_from(COMMAND.network_endpoint())
+            .map_err(|_| Error::ServiceException(1022))?,
+    );
+
+    let registry =
+        indexer_registry(client, COMMAND.network()).map_err(|_| Error::ServiceException(1023))?;
+
+    let controller: Address = registry
+        .method::<_, Address>("getController", (indexer,))
+        .map_err(|_| Error::ServiceException(1210))?
+        .call()
+        .await
+        .map_err(|_| Error::ServiceException(1210))?;
+    Ok(controller)
+}
+
 pub async fn check_convert_price(
     asset_from: Address,
     amount_from: U256,
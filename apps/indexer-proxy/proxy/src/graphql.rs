@@ -40,12 +40,29 @@ pub const PROJECT_QUERY: &str =
 
 pub const PAYG_QUERY: &str = "query { getAlivePaygs { id price token expiration overflow } }";
 
+/// the AI projects token prices, not supported by old coordinator
+pub const PAYG_AI_QUERY: &str =
+    "query { getAlivePaygs { id outputPrice modelPrices { model price outputPrice } } }";
+
 pub const CHANNEL_QUERY: &str =
     "query { getAliveChannels { id consumer deploymentId agent total spent remote price lastFinal expiredAt } }";
 
 pub const AUTO_REDUCE_ALLOCATION: &str =
     r#"query { config(key: "auto_reduce_allocation_enabled") }"#;
 
+/// the id of `_poi` is BigInt (string literal) in query service, and Int in old versions
+pub fn poi_query(height: u64, bigint: bool) -> String {
+    let id = if bigint {
+        format!("\"{}\"", height)
+    } else {
+        height.to_string()
+    };
+    format!(
+        "query {{ _poi(id: {}) {{ id chainBlockHash hash parentHash operationHashRoot }} }}",
+        id
+    )
+}
+
 pub fn project_mainfest(project_type: i64, project_id: &str) -> String {
     format!(
         r#"query {{
@@ -0,0 +1,248 @@
+// This file is part of SubQuery.
+
+// Copyright (C) 2020-2024 SubQuery Pte Ltd authors & contributors
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Track the project lag (targetHeight - lastHeight) and admission the paid query.
+//! The lag is updated when the metadata cache refreshed. The paid queries of lagging
+//! projects are rejected, or deprioritized into a small lane with limited concurrency.
+
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use serde_json::Value;
+use std::{collections::HashMap, sync::Arc};
+use subql_indexer_utils::{error::Error, types::Result};
+use tokio::sync::{OwnedSemaphorePermit, RwLock, Semaphore};
+use tokio::time::{timeout, Duration};
+
+use crate::cli::COMMAND;
+use crate::metrics::update_metrics_lag;
+use crate::primitives::{LAG_DEPRIORITIZE_PERMITS, LAG_DEPRIORITIZE_WAIT, LAG_STALE_TIME};
+
+#[derive(Clone, Debug, Default)]
+pub struct ProjectLag {
+    pub last_height: u64,
+    pub target_height: u64,
+    pub healthy: bool,
+    /// the rpc node is catching up, the network head maybe unknown
+    pub syncing: bool,
+    pub updated_at: i64,
+}
+
+impl ProjectLag {
+    pub fn from_metadata(metadata: &Value) -> Self {
+        let last_height = value_u64(&metadata["lastHeight"]);
+        let target_height = value_u64(&metadata["targetHeight"]);
+        // rpc projects have no indexer healthy, default is healthy,
+        // and the node still syncing (cosmos & near) is unhealthy
+        let syncing = metadata["catchingUp"].as_bool().unwrap_or(false)
+            || metadata["syncing"].as_bool().unwrap_or(false);
+        let healthy = metadata["subqueryHealthy"].as_bool().unwrap_or(true) && !syncing;
+
+        Self {
+            last_height,
+            target_height,
+            healthy,
+            syncing,
+            updated_at: Utc::now().timestamp(),
+        }
+    }
+
+    pub fn lag(&self) -> u64 {
+        self.target_height.saturating_sub(self.last_height)
+    }
+
+    /// the syncing node is lagging even when it not know the network head
+    pub fn is_lagging(&self, threshold: u64) -> bool {
+        self.syncing || self.lag() > threshold
+    }
+
+    fn is_stale(&self) -> bool {
+        Utc::now().timestamp() - self.updated_at > LAG_STALE_TIME
+    }
+}
+
+fn value_u64(v: &Value) -> u64 {
+    v.as_u64()
+        .or(v.as_str().and_then(|s| s.parse().ok()))
+        .unwrap_or(0)
+}
+
+/// the lane of deprioritized queries, shared by all lagging projects
+static DEPRIORITIZED: Lazy<Arc<Semaphore>> =
+    Lazy::new(|| Arc::new(Semaphore::new(LAG_DEPRIORITIZE_PERMITS)));
+
+/// project => lag
+static PROJECT_LAGS: Lazy<RwLock<HashMap<String, ProjectLag>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// only keep the lag of current projects
+pub async fn retain_lags(deployments: &[String]) {
+    let mut lock = PROJECT_LAGS.write().await;
+    lock.retain(|k, _| deployments.contains(k));
+    drop(lock);
+}
+
+pub async fn update_lag(deployment: &str, lag: ProjectLag) {
+    update_metrics_lag(deployment.to_owned(), lag.lag(), lag.healthy).await;
+
+    let mut lock = PROJECT_LAGS.write().await;
+    lock.insert(deployment.to_owned(), lag);
+    drop(lock);
+}
+
+/// get the current lag blocks of project, None if not tracked or stale
+pub async fn get_lag(deployment: &str) -> Option<u64> {
+    let lock = PROJECT_LAGS.read().await;
+    let lag = lock
+        .get(deployment)
+        .filter(|l| !l.is_stale())
+        .map(|l| l.lag());
+    drop(lock);
+    lag
+}
+
+/// check the project lag when paid query, reject or deprioritize it when exceeds threshold,
+/// the returned permit of deprioritized lane need be kept until the query finished
+pub async fn check_lag(deployment: &str) -> Result<Option<OwnedSemaphorePermit>> {
+    let threshold = COMMAND.lag_threshold(deployment);
+    if threshold == 0 {
+        return Ok(None);
+    }
+
+    let lock = PROJECT_LAGS.read().await;
+    let lag = lock.get(deployment).filter(|l| !l.is_stale()).cloned();
+    drop(lock);
+
+    admit(
+        lag.as_ref(),
+        threshold,
+        COMMAND.lag_deprioritize(),
+        &DEPRIORITIZED,
+    )
+    .await
+}
+
+async fn admit(
+    lag: Option<&ProjectLag>,
+    threshold: u64,
+    deprioritize: bool,
+    lane: &Arc<Semaphore>,
+) -> Result<Option<OwnedSemaphorePermit>> {
+    match lag {
+        Some(lag) if lag.is_lagging(threshold) => {
+            if !deprioritize {
+                return Err(Error::Lagging(1072));
+            }
+            // wait the lane, healthy projects never wait it
+            match timeout(
+                Duration::from_secs(LAG_DEPRIORITIZE_WAIT),
+                lane.clone().acquire_owned(),
+            )
+            .await
+            {
+                Ok(Ok(permit)) => Ok(Some(permit)),
+                _ => Err(Error::Lagging(1086)),
+            }
+        }
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn project_lag(last_height: u64, target_height: u64) -> ProjectLag {
+        ProjectLag {
+            last_height,
+            target_height,
+            healthy: true,
+            syncing: false,
+            updated_at: Utc::now().timestamp(),
+        }
+    }
+
+    fn code(res: Result<Option<OwnedSemaphorePermit>>) -> i32 {
+        match res {
+            Err(Error::Lagging(code)) => code,
+            _ => 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn reject_policy() {
+        let lane = Arc::new(Semaphore::new(1));
+        let below = project_lag(95, 100);
+        let at = project_lag(90, 100);
+        let above = project_lag(89, 100);
+
+        assert!(admit(Some(&below), 10, false, &lane)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(admit(Some(&at), 10, false, &lane).await.unwrap().is_none());
+        assert_eq!(code(admit(Some(&above), 10, false, &lane).await), 1072);
+        // not tracked project
+        assert!(admit(None, 10, false, &lane).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn deprioritize_policy() {
+        let lane = Arc::new(Semaphore::new(1));
+        let below = project_lag(95, 100);
+        let at = project_lag(90, 100);
+        let above = project_lag(89, 100);
+
+        assert!(admit(Some(&below), 10, true, &lane)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(admit(Some(&at), 10, true, &lane).await.unwrap().is_none());
+
+        // the lagging query hold the lane
+        let permit = admit(Some(&above), 10, true, &lane).await.unwrap();
+        assert!(permit.is_some());
+        assert_eq!(lane.available_permits(), 0);
+
+        // healthy query not wait the full lane
+        assert!(admit(Some(&below), 10, true, &lane)
+            .await
+            .unwrap()
+            .is_none());
+
+        drop(permit);
+        assert_eq!(lane.available_permits(), 1);
+    }
+
+    #[tokio::test]
+    async fn syncing_node_is_lagging() {
+        let lane = Arc::new(Semaphore::new(1));
+        // rpc node report targetHeight same as lastHeight
+        let mut lag = project_lag(100, 100);
+        lag.syncing = true;
+        assert_eq!(code(admit(Some(&lag), 10, false, &lane).await), 1072);
+
+        let metadata = serde_json::json!({
+            "lastHeight": 100,
+            "targetHeight": 100,
+            "catchingUp": true,
+        });
+        let lag = ProjectLag::from_metadata(&metadata);
+        assert!(lag.syncing && !lag.healthy);
+        assert!(lag.is_lagging(10));
+    }
+}
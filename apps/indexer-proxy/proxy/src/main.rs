@@ -22,16 +22,19 @@ extern crate tracing;
 
 mod account;
 mod ai;
+mod ai_queue;
 mod auth;
 mod cli;
 mod contracts;
 mod graphql;
+mod lag;
 mod metadata;
 mod metrics;
 mod mod_libp2p;
 mod monitor;
 // mod p2p;
 mod payg;
+mod poi;
 mod primitives;
 mod project;
 mod response;
@@ -87,6 +90,7 @@ fn start_tokio_main() {
 
         subscriber::subscribe();
         monitor::listen();
+        metadata::listen();
         // p2p::listen();
         metrics::listen();
         whitelist::listen();
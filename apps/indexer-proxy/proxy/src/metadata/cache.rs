@@ -0,0 +1,138 @@
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use subql_indexer_utils::{error::Error, tools::merge_json, types::Result};
+use tokio::sync::{Mutex, RwLock};
+use tokio::time::{sleep, Duration};
+
+use crate::lag::{retain_lags, update_lag, ProjectLag};
+use crate::metrics::MetricsNetwork;
+use crate::primitives::{METADATA_FAILURE_TIME, METADATA_LOOP_TIME};
+use crate::project::{list_projects, Project};
+
+#[derive(Default)]
+struct MetadataCache {
+    /// signed metadata
+    value: Option<Value>,
+    /// the timestamp of value cached
+    cached_at: i64,
+    /// the timestamp of next refresh
+    next_refresh: i64,
+    /// had a refresh task running
+    refreshing: bool,
+    /// the last fetch failure and the timestamp, when no value cached
+    failure: Option<(Error, i64)>,
+    /// only one request fetch the upstream at the same time
+    flight: Arc<Mutex<()>>,
+}
+
+/// project => metadata cache
+static METADATA_CACHE: Lazy<RwLock<HashMap<String, MetadataCache>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+pub fn listen() {
+    tokio::spawn(async {
+        loop {
+            sleep(Duration::from_secs(METADATA_LOOP_TIME)).await;
+
+            let projects = list_projects().await;
+            let ids: Vec<String> = projects.iter().map(|p| p.id.clone()).collect();
+            let now = Utc::now().timestamp();
+
+            let mut lock = METADATA_CACHE.write().await;
+            lock.retain(|k, _| ids.contains(k));
+            for project in projects {
+                let cache = lock.entry(project.id.clone()).or_default();
+                if cache.refreshing || cache.next_refresh > now {
+                    continue;
+                }
+                cache.refreshing = true;
+                let flight = cache.flight.clone();
+                tokio::spawn(async move {
+                    let _guard = flight.lock().await;
+                    let _ = refresh(&project).await;
+                });
+            }
+            drop(lock);
+
+            retain_lags(&ids).await;
+        }
+    });
+}
+
+/// fetch the metadata from upstream, and update the cache
+async fn refresh(project: &Project) -> Result<Value> {
+    let res = match project.raw_metadata(MetricsNetwork::HTTP).await {
+        Ok(raw) => {
+            if !project.is_ai_project() {
+                update_lag(&project.id, ProjectLag::from_metadata(&raw)).await;
+            }
+            project.sign_metadata(raw).await
+        }
+        Err(err) => Err(err),
+    };
+
+    let now = Utc::now().timestamp();
+    let mut lock = METADATA_CACHE.write().await;
+    let cache = lock.entry(project.id.clone()).or_default();
+    cache.refreshing = false;
+    cache.next_refresh = now + project.metadata_refresh_time() as i64;
+    match &res {
+        Ok(value) => {
+            cache.value = Some(value.clone());
+            cache.cached_at = now;
+            cache.failure = None;
+        }
+        Err(err) => {
+            warn!("Refresh project {} metadata failure: {:?}", project.id, err);
+            cache.failure = Some((err.clone(), now));
+        }
+    }
+    drop(lock);
+
+    res.map(|v| with_cached_at(v, now))
+}
+
+/// get the cached metadata, fetch it directly if not cached,
+/// concurrent requests share one upstream fetch
+pub async fn cached_metadata(project: &Project) -> Result<Value> {
+    if let Some(res) = lookup(project).await {
+        return res;
+    }
+
+    let flight = METADATA_CACHE
+        .write()
+        .await
+        .entry(project.id.clone())
+        .or_default()
+        .flight
+        .clone();
+    let _guard = flight.lock().await;
+
+    // the other request maybe had fetched when waiting
+    if let Some(res) = lookup(project).await {
+        return res;
+    }
+    refresh(project).await
+}
+
+/// the cached value, or the recent failure
+async fn lookup(project: &Project) -> Option<Result<Value>> {
+    let now = Utc::now().timestamp();
+    let lock = METADATA_CACHE.read().await;
+    let cache = lock.get(&project.id)?;
+    if let Some(value) = &cache.value {
+        return Some(Ok(with_cached_at(value.clone(), cache.cached_at)));
+    }
+    match &cache.failure {
+        Some((err, failed_at)) if now - failed_at < METADATA_FAILURE_TIME => Some(Err(err.clone())),
+        _ => None,
+    }
+}
+
+fn with_cached_at(mut value: Value, cached_at: i64) -> Value {
+    merge_json(&mut value, &json!({ "cachedAt": cached_at }));
+    value
+}
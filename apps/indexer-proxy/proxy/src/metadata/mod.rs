@@ -1,18 +1,41 @@
 mod ai;
+mod cache;
+mod rpc_bitcoin;
+mod rpc_cosmos;
 mod rpc_evm;
+mod rpc_near;
+mod rpc_starknet;
 mod rpc_substrate;
 mod subgraph;
 mod subquery;
 
 pub use ai::metadata as ai_metadata;
+pub use cache::{cached_metadata, listen};
+pub use rpc_bitcoin::metadata as rpc_bitcoin_metadata;
+pub use rpc_cosmos::metadata as rpc_cosmos_metadata;
 pub use rpc_evm::metadata as rpc_evm_metadata;
+pub use rpc_near::metadata as rpc_near_metadata;
+pub use rpc_starknet::metadata as rpc_starknet_metadata;
 pub use rpc_substrate::metadata as rpc_substrate_metadata;
 pub use subgraph::metadata as subgraph_metadata;
 pub use subquery::metadata as subquery_metadata;
 
+use once_cell::sync::Lazy;
+use serde_json::Value;
+use std::{collections::HashMap, future::Future, time::Instant};
+use subql_indexer_utils::{
+    request::{graphql_request, jsonrpc_request, GraphQLQuery},
+    types::Result,
+};
+use tokio::sync::RwLock;
+
 use crate::cli::COMMAND;
 use crate::graphql::AUTO_REDUCE_ALLOCATION;
-use subql_indexer_utils::request::{graphql_request, GraphQLQuery};
+use crate::metrics::{add_metrics_query, MetricsNetwork, MetricsQuery};
+use crate::project::Project;
+
+/// endpoint => the first available block of pruned node
+static FIRST_BLOCKS: Lazy<RwLock<HashMap<String, u64>>> = Lazy::new(|| RwLock::new(HashMap::new()));
 
 pub async fn auto_reduce_allocation_enabled() -> Option<bool> {
     let url = COMMAND.graphql_url();
@@ -27,3 +50,319 @@ pub async fn auto_reduce_allocation_enabled() -> Option<bool> {
         Err(_) => None,
     }
 }
+
+/// jsonrpc request to project endpoint, and record the query time to metrics
+async fn project_jsonrpc(
+    project: &Project,
+    network: MetricsNetwork,
+    url: &str,
+    method: &str,
+    params: Vec<Value>,
+) -> Result<Value> {
+    let now = Instant::now();
+    let res = jsonrpc_request(url, method, params).await;
+    let time = now.elapsed().as_millis() as u64;
+    add_metrics_query(
+        project.id.clone(),
+        Some(time),
+        MetricsQuery::Free,
+        network,
+        res.is_ok(),
+    );
+    res
+}
+
+/// the first available block of pruned node, binary search in (low, high],
+/// the block high must be available. the pruned blocks only grow, so start from the cached one
+async fn first_available_block<F, Fut>(url: &str, low: u64, high: u64, available: F) -> u64
+where
+    F: Fn(u64) -> Fut,
+    Fut: Future<Output = bool>,
+{
+    let cached = FIRST_BLOCKS.read().await.get(url).copied();
+    let mut low = low;
+    if let Some(first) = cached.filter(|f| *f > low && *f <= high) {
+        if available(first).await {
+            return first;
+        }
+        low = first;
+    }
+
+    let mut high = high;
+    while high - low > 1 {
+        let mid = low + (high - low) / 2;
+        if available(mid).await {
+            high = mid;
+        } else {
+            low = mid;
+        }
+    }
+
+    FIRST_BLOCKS.write().await.insert(url.to_owned(), high);
+    high
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::project::{Endpoint, ProjectType, RpcMainfest};
+    use axum::{routing::post, Json, Router};
+    use ethers::types::{Address, U256};
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    /// start a local jsonrpc server, response the result by method with params
+    /// (e.g. `chain_getHeader["0xaa"]`), or by method
+    async fn mock_jsonrpc(results: Vec<(&'static str, Value)>) -> String {
+        let results: HashMap<&'static str, Value> = results.into_iter().collect();
+        let app = Router::new().route(
+            "/",
+            post(move |Json(req): Json<Value>| async move {
+                let method = req["method"].as_str().unwrap_or("");
+                let with_params = format!("{}{}", method, req["params"]);
+                let result = results
+                    .get(with_params.as_str())
+                    .or(results.get(method))
+                    .cloned()
+                    .unwrap_or(Value::Null);
+                Json(json!({ "jsonrpc": "2.0", "id": req["id"], "result": result }))
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}", addr)
+    }
+
+    fn mock_project(ptype: ProjectType, endpoint: String) -> Project {
+        let mut endpoints = HashMap::new();
+        endpoints.insert(
+            "default".to_owned(),
+            Endpoint {
+                endpoint,
+                is_internal: false,
+                is_ws: false,
+                rpc_family: vec![],
+            },
+        );
+        Project {
+            id: "QmMock".to_owned(),
+            ptype,
+            endpoints,
+            rate_limit: None,
+            db_size: None,
+            payg_price: U256::zero(),
+            payg_token: Address::zero(),
+            payg_expiration: 0,
+            payg_overflow: 0,
+            payg_output_price: U256::zero(),
+            payg_model_prices: HashMap::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn cosmos_metadata() {
+        let url = mock_jsonrpc(vec![
+            (
+                "genesis",
+                json!({ "genesis": { "chain_id": "cosmoshub-4", "initial_height": "1" } }),
+            ),
+            (
+                "status",
+                json!({
+                    "node_info": { "network": "cosmoshub-4" },
+                    "sync_info": {
+                        "earliest_block_hash": "ABCD",
+                        "earliest_block_height": "1",
+                        "latest_block_height": "20000000",
+                        "latest_block_time": "2024-04-01T00:00:00.123456789Z",
+                        "catching_up": false
+                    }
+                }),
+            ),
+        ])
+        .await;
+        let project = mock_project(ProjectType::RpcCosmos(RpcMainfest::default()), url);
+        let data = project.raw_metadata(MetricsNetwork::HTTP).await.unwrap();
+        assert_eq!(data["lastHeight"], 20000000);
+        assert_eq!(data["startHeight"], 1);
+        assert_eq!(data["lastTime"], 1711929600);
+        assert_eq!(data["genesis"], "ABCD");
+        assert_eq!(data["chainId"], "cosmoshub-4");
+    }
+
+    #[tokio::test]
+    async fn near_metadata() {
+        let url = mock_jsonrpc(vec![(
+            "status",
+            json!({
+                "chain_id": "mainnet",
+                "genesis_hash": "EPnLgE7iEq9s7yTkos96M3cWymH5avBAPm3qx3NXqR8H",
+                "sync_info": {
+                    "earliest_block_height": 100,
+                    "latest_block_height": 120000000,
+                    "latest_block_time": "2024-04-01T00:00:00.000000000Z",
+                    "syncing": false
+                }
+            }),
+        )])
+        .await;
+        let project = mock_project(ProjectType::RpcNear(RpcMainfest::default()), url);
+        let data = project.raw_metadata(MetricsNetwork::HTTP).await.unwrap();
+        assert_eq!(data["lastHeight"], 120000000);
+        assert_eq!(data["startHeight"], 100);
+        assert_eq!(data["lastTime"], 1711929600);
+        assert_eq!(data["chainId"], "mainnet");
+    }
+
+    #[tokio::test]
+    async fn starknet_metadata() {
+        let url = mock_jsonrpc(vec![
+            ("starknet_blockNumber", json!(650000)),
+            ("starknet_chainId", json!("0x534e5f4d41494e")),
+            (
+                "starknet_syncing",
+                json!({ "current_block_num": 650000, "highest_block_num": 650010 }),
+            ),
+            (
+                "starknet_getBlockWithTxHashes",
+                json!({ "block_hash": "0x47c3", "timestamp": 1711929600 }),
+            ),
+        ])
+        .await;
+        let project = mock_project(ProjectType::RpcStarknet(RpcMainfest::default()), url);
+        let data = project.raw_metadata(MetricsNetwork::HTTP).await.unwrap();
+        assert_eq!(data["lastHeight"], 650000);
+        assert_eq!(data["targetHeight"], 650010);
+        assert_eq!(data["startHeight"], 0);
+        assert_eq!(data["genesis"], "0x47c3");
+        assert_eq!(data["lastTime"], 1711929600);
+        assert_eq!(data["chainId"], "SN_MAIN");
+    }
+
+    #[tokio::test]
+    async fn starknet_pruned_metadata() {
+        let url = mock_jsonrpc(vec![
+            ("starknet_blockNumber", json!(1000)),
+            ("starknet_chainId", json!("0x534e5f4d41494e")),
+            ("starknet_syncing", json!(false)),
+            (
+                "starknet_getBlockWithTxHashes",
+                json!({ "block_hash": "0x47c3", "timestamp": 1711929600 }),
+            ),
+            // the blocks before 501 are pruned
+            (
+                r#"starknet_getBlockWithTxHashes[{"block_number":0}]"#,
+                Value::Null,
+            ),
+            (
+                r#"starknet_getBlockWithTxHashes[{"block_number":500}]"#,
+                Value::Null,
+            ),
+        ])
+        .await;
+        let project = mock_project(ProjectType::RpcStarknet(RpcMainfest::default()), url);
+        let data = project.raw_metadata(MetricsNetwork::HTTP).await.unwrap();
+        assert_eq!(data["lastHeight"], 1000);
+        assert_eq!(data["targetHeight"], 1000);
+        assert_eq!(data["startHeight"], 501);
+        assert_eq!(data["pruned"], true);
+        assert_eq!(data["genesis"], "");
+    }
+
+    #[tokio::test]
+    async fn substrate_metadata() {
+        let url = mock_jsonrpc(vec![
+            // best block 21000000, finalized block 20999998
+            ("chain_getBlockHash[]", json!("0xbbbb")),
+            (
+                r#"chain_getHeader["0xbbbb"]"#,
+                json!({ "number": "0x1406f40" }),
+            ),
+            ("chain_getFinalizedHead", json!("0xaaaa")),
+            (
+                r#"chain_getHeader["0xaaaa"]"#,
+                json!({ "number": "0x1406f3e" }),
+            ),
+            ("chain_getBlockHash[0]", json!("0x91b1")),
+            ("chain_getBlockHash", json!("0x0001")),
+            // 1711929600000 ms
+            ("state_getStorage", json!("0x00a8f4968e010000")),
+            (
+                "state_getRuntimeVersion",
+                json!({ "specName": "polkadot", "specVersion": 1002000 }),
+            ),
+            ("system_chain", json!("Polkadot")),
+        ])
+        .await;
+        let project = mock_project(ProjectType::RpcSubstrate(RpcMainfest::default()), url);
+        let data = project.raw_metadata(MetricsNetwork::HTTP).await.unwrap();
+        assert_eq!(data["lastHeight"], 21000000);
+        assert_eq!(data["finalizedHeight"], 20999998);
+        assert_eq!(data["startHeight"], 1);
+        assert_eq!(data["pruned"], false);
+        assert_eq!(data["genesis"], "0x91b1");
+        assert_eq!(data["lastTime"], 1711929600);
+        assert_eq!(data["specVersion"], 1002000);
+        assert_eq!(data["chainId"], "Polkadot");
+    }
+
+    #[tokio::test]
+    async fn substrate_pruned_metadata() {
+        let url = mock_jsonrpc(vec![
+            ("chain_getBlockHash[]", json!("0xbbbb")),
+            (r#"chain_getHeader["0xbbbb"]"#, json!({ "number": "0x3e8" })),
+            ("chain_getFinalizedHead", json!("0xaaaa")),
+            (r#"chain_getHeader["0xaaaa"]"#, json!({ "number": "0x3e6" })),
+            ("chain_getBlockHash[1]", json!("0x0001")),
+            ("chain_getBlockHash[500]", json!("0x01f4")),
+            ("chain_getBlockHash", json!("0x0002")),
+            // the states of block 1 and 500 are pruned, keep after 501
+            (r#"state_getStorage["0xf0c365c3cf59d671eb72da0e7a4113c49f1f0515f462cdcf84e0f1d6045dfcbb","0x0001"]"#, Value::Null),
+            (r#"state_getStorage["0xf0c365c3cf59d671eb72da0e7a4113c49f1f0515f462cdcf84e0f1d6045dfcbb","0x01f4"]"#, Value::Null),
+            ("state_getStorage", json!("0x00a8f4968e010000")),
+            ("system_chain", json!("Polkadot")),
+        ])
+        .await;
+        let project = mock_project(ProjectType::RpcSubstrate(RpcMainfest::default()), url);
+        let data = project.raw_metadata(MetricsNetwork::HTTP).await.unwrap();
+        assert_eq!(data["lastHeight"], 1000);
+        assert_eq!(data["finalizedHeight"], 998);
+        assert_eq!(data["startHeight"], 501);
+        assert_eq!(data["pruned"], true);
+    }
+
+    #[tokio::test]
+    async fn bitcoin_metadata() {
+        let url = mock_jsonrpc(vec![
+            (
+                "getblockchaininfo",
+                json!({
+                    "chain": "main",
+                    "blocks": 840000,
+                    "headers": 840010,
+                    "mediantime": 1713570000,
+                    "time": 1713571767,
+                    "pruned": true,
+                    "pruneheight": 780000
+                }),
+            ),
+            (
+                "getblockhash",
+                json!("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"),
+            ),
+        ])
+        .await;
+        let project = mock_project(ProjectType::RpcBitcoin(RpcMainfest::default()), url);
+        let data = project.raw_metadata(MetricsNetwork::HTTP).await.unwrap();
+        assert_eq!(data["lastHeight"], 840000);
+        assert_eq!(data["targetHeight"], 840010);
+        assert_eq!(data["startHeight"], 780000);
+        assert_eq!(
+            data["genesis"],
+            "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"
+        );
+        assert_eq!(data["lastTime"], 1713571767);
+        assert_eq!(data["chainId"], "main");
+    }
+}
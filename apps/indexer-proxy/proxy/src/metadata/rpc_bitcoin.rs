@@ -0,0 +1,61 @@
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use subql_indexer_utils::types::Result;
+use tokio::sync::RwLock;
+
+use super::project_jsonrpc;
+use crate::metrics::MetricsNetwork;
+use crate::project::Project;
+
+/// endpoint => the genesis block hash, it never changes
+static GENESIS: Lazy<RwLock<HashMap<String, String>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// rpc bitcoin
+pub async fn metadata(project: &Project, network: MetricsNetwork) -> Result<Value> {
+    let url = &project.endpoint("default", true)?.endpoint;
+
+    let info = project_jsonrpc(project, network, url, "getblockchaininfo", vec![]).await?;
+
+    let genesis = genesis(project, network, url).await?;
+
+    let last_height = info["blocks"].as_u64().unwrap_or(0);
+    // headers is the best known header height, the node is syncing when less than it
+    let target_height = info["headers"].as_u64().unwrap_or(last_height);
+    // pruned node only keep the blocks after pruneheight
+    let start_height = if info["pruned"].as_bool().unwrap_or(false) {
+        info["pruneheight"].as_u64().unwrap_or(0)
+    } else {
+        0
+    };
+    let last_time = info["time"]
+        .as_u64()
+        .or(info["mediantime"].as_u64())
+        .unwrap_or(0);
+
+    Ok(json!({
+        "startHeight": start_height,
+        "lastHeight": last_height,
+        "targetHeight": target_height,
+        "lastTime": last_time,
+        "genesis": genesis,
+        "chainId": info["chain"].as_str().unwrap_or(""),
+    }))
+}
+
+/// fetch the genesis block hash once, and cache it
+async fn genesis(project: &Project, network: MetricsNetwork, url: &str) -> Result<String> {
+    if let Some(hash) = GENESIS.read().await.get(url) {
+        return Ok(hash.clone());
+    }
+
+    let hash = project_jsonrpc(project, network, url, "getblockhash", vec![json!(0)])
+        .await?
+        .as_str()
+        .unwrap_or("")
+        .to_owned();
+    if !hash.is_empty() {
+        GENESIS.write().await.insert(url.to_owned(), hash.clone());
+    }
+    Ok(hash)
+}
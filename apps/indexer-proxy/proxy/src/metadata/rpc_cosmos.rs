@@ -0,0 +1,101 @@
+use base64::{engine::general_purpose, Engine as _};
+use chrono::DateTime;
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use subql_indexer_utils::types::Result;
+use tokio::sync::RwLock;
+
+use super::project_jsonrpc;
+use crate::metrics::MetricsNetwork;
+use crate::project::Project;
+
+/// endpoint => the initial height in genesis, it never changes
+static INITIAL_HEIGHTS: Lazy<RwLock<HashMap<String, u64>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// rpc cosmos (tendermint/cometbft)
+pub async fn metadata(project: &Project, network: MetricsNetwork) -> Result<Value> {
+    let url = &project.endpoint("default", true)?.endpoint;
+
+    let status = project_jsonrpc(project, network, url, "status", vec![]).await?;
+    let initial_height = initial_height(project, network, url).await;
+    Ok(parse_status(&status, initial_height))
+}
+
+/// read the initial_height from genesis, the big genesis only can read by chunks,
+/// and initial_height is in the head of first chunk
+async fn initial_height(project: &Project, network: MetricsNetwork, url: &str) -> Option<u64> {
+    if let Some(height) = INITIAL_HEIGHTS.read().await.get(url) {
+        return Some(*height);
+    }
+
+    let height = match project_jsonrpc(project, network, url, "genesis", vec![]).await {
+        Ok(genesis) => genesis["genesis"]["initial_height"]
+            .as_str()
+            .and_then(|s| s.parse().ok()),
+        Err(_) => {
+            let chunk = project_jsonrpc(project, network, url, "genesis_chunked", vec![json!("0")])
+                .await
+                .ok()?;
+            let data = general_purpose::STANDARD
+                .decode(chunk["data"].as_str().unwrap_or(""))
+                .ok()?;
+            parse_initial_height(&String::from_utf8_lossy(&data))
+        }
+    }?;
+
+    INITIAL_HEIGHTS.write().await.insert(url.to_owned(), height);
+    Some(height)
+}
+
+fn parse_initial_height(genesis: &str) -> Option<u64> {
+    let key = "\"initial_height\"";
+    let rest = &genesis[genesis.find(key)? + key.len()..];
+    let digits: String = rest
+        .trim_start_matches(|c: char| c == ':' || c == '"' || c.is_whitespace())
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .collect();
+    digits.parse().ok()
+}
+
+/// the node has no view of the network head, so the targetHeight is same as lastHeight,
+/// and the lag is always 0, use catchingUp to know the node is syncing
+fn parse_status(status: &Value, initial_height: Option<u64>) -> Value {
+    let height = |key: &str| -> u64 {
+        status["sync_info"][key]
+            .as_str()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(0)
+    };
+    let last_height = height("latest_block_height");
+    let start_height = height("earliest_block_height");
+    let last_time = status["sync_info"]["latest_block_time"]
+        .as_str()
+        .and_then(|s| DateTime::parse_from_rfc3339(s).ok())
+        .map(|t| t.timestamp())
+        .unwrap_or(0);
+    let chain = status["node_info"]["network"].as_str().unwrap_or("");
+    // only the node keep the first block can know the genesis
+    let genesis = if initial_height == Some(start_height) {
+        status["sync_info"]["earliest_block_hash"]
+            .as_str()
+            .unwrap_or("")
+    } else {
+        ""
+    };
+    let catching_up = status["sync_info"]["catching_up"]
+        .as_bool()
+        .unwrap_or(false);
+
+    json!({
+        "startHeight": start_height,
+        "lastHeight": last_height,
+        "targetHeight": last_height,
+        "lastTime": last_time,
+        "genesis": genesis,
+        "chainId": chain,
+        "catchingUp": catching_up,
+    })
+}
@@ -1,7 +1,8 @@
 use ethers::prelude::*;
 use serde_json::{json, Value};
-use solana_client::rpc_client::RpcClient;
+use solana_client::{nonblocking::rpc_client::RpcClient, rpc_config::RpcBlockConfig};
 use solana_sdk::{bs58, commitment_config::CommitmentConfig};
+use solana_transaction_status_client_types::TransactionDetails;
 use std::time::Instant;
 use subql_indexer_utils::{error::Error, types::Result};
 
@@ -61,6 +62,13 @@ pub async fn evm_metadata(
     let last_height = last_block.number.unwrap_or(U64::zero()).as_u64();
     let last_time = last_block.timestamp.as_u64();
 
+    // the syncing node know the highest block of network
+    let target_height = match provider.syncing().await {
+        Ok(SyncingStatus::IsSyncing(progress)) => progress.highest_block.as_u64(),
+        _ => last_height,
+    }
+    .max(last_height);
+
     let now = Instant::now();
     let genesis_block = provider
         .get_block(BlockNumber::Earliest)
@@ -81,7 +89,7 @@ pub async fn evm_metadata(
     Ok(json!({
         "startHeight": start_height,
         "lastHeight": last_height,
-        "targetHeight": last_height,
+        "targetHeight": target_height,
         "lastTime": last_time,
         "genesis": genesis,
         "chainId": chain,
@@ -98,6 +106,7 @@ pub async fn solana_metadata(
         RpcClient::new_with_commitment(endpoint.endpoint.clone(), CommitmentConfig::confirmed());
 
     let now = Instant::now();
+    let (last_height, last_time) = solana_get_latest_block_height_and_time(&client).await?;
     let time = now.elapsed().as_millis() as u64;
     add_metrics_query(
         project.id.clone(),
@@ -108,8 +117,7 @@ pub async fn solana_metadata(
     );
 
     let now = Instant::now();
-
-    let (last_height, last_time) = solana_get_latest_block_height_and_time(&client).await?;
+    let start_height = solana_get_start_height(&client).await?;
     let time = now.elapsed().as_millis() as u64;
     add_metrics_query(
         project.id.clone(),
@@ -118,14 +126,11 @@ pub async fn solana_metadata(
         network,
         true,
     );
+
     let now = Instant::now();
-    // let genesis_block = client
-    //     .get_block(0)
-    //     .map_err(|_| Error::ServiceException(1201))
-    //     .unwrap_or_default();
-    let start_height = 0;
     let genesis = client
         .get_genesis_hash()
+        .await
         .map_err(|_| Error::ServiceException(1201))?;
     let base58_hash = bs58::encode(genesis.to_bytes()).into_string();
     let time = now.elapsed().as_millis() as u64;
@@ -147,15 +152,37 @@ pub async fn solana_metadata(
 }
 
 async fn solana_get_latest_block_height_and_time(client: &RpcClient) -> Result<(u64, i64)> {
-    match client.get_latest_blockhash_with_commitment(CommitmentConfig::confirmed()) {
-        Ok((_hash, height)) => {
-            if let Ok(slot) = client.get_slot() {
-                if let Ok(block_time) = client.get_block_time(slot) {
-                    return Ok((height, block_time));
-                }
-            }
-            Err(Error::ServiceException(1201))
-        }
-        Err(_err) => Err(Error::ServiceException(1201)),
-    }
+    let height = client
+        .get_block_height()
+        .await
+        .map_err(|_| Error::ServiceException(1201))?;
+    let slot = client
+        .get_slot()
+        .await
+        .map_err(|_| Error::ServiceException(1201))?;
+    let block_time = client
+        .get_block_time(slot)
+        .await
+        .map_err(|_| Error::ServiceException(1201))?;
+    Ok((height, block_time))
+}
+
+/// the first block still available in the node ledger, pruned node not start from genesis.
+async fn solana_get_start_height(client: &RpcClient) -> Result<u64> {
+    let slot = client
+        .get_first_available_block()
+        .await
+        .map_err(|_| Error::ServiceException(1201))?;
+    let config = RpcBlockConfig {
+        encoding: None,
+        transaction_details: Some(TransactionDetails::None),
+        rewards: Some(false),
+        commitment: Some(CommitmentConfig::confirmed()),
+        max_supported_transaction_version: Some(0),
+    };
+    let block = client
+        .get_block_with_config(slot, config)
+        .await
+        .map_err(|_| Error::ServiceException(1201))?;
+    Ok(block.block_height.unwrap_or(slot))
 }
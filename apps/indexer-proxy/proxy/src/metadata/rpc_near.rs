@@ -0,0 +1,42 @@
+use chrono::DateTime;
+use serde_json::{json, Value};
+use subql_indexer_utils::types::Result;
+
+use super::project_jsonrpc;
+use crate::metrics::MetricsNetwork;
+use crate::project::Project;
+
+/// rpc near
+pub async fn metadata(project: &Project, network: MetricsNetwork) -> Result<Value> {
+    let url = &project.endpoint("default", true)?.endpoint;
+
+    let status = project_jsonrpc(project, network, url, "status", vec![]).await?;
+    Ok(parse_status(&status))
+}
+
+/// the node has no view of the network head, so the targetHeight is same as lastHeight,
+/// and the lag is always 0, use syncing to know the node is syncing
+fn parse_status(status: &Value) -> Value {
+    let last_height = status["sync_info"]["latest_block_height"]
+        .as_u64()
+        .unwrap_or(0);
+    let start_height = status["sync_info"]["earliest_block_height"]
+        .as_u64()
+        .unwrap_or(0);
+    let last_time = status["sync_info"]["latest_block_time"]
+        .as_str()
+        .and_then(|s| DateTime::parse_from_rfc3339(s).ok())
+        .map(|t| t.timestamp())
+        .unwrap_or(0);
+    let syncing = status["sync_info"]["syncing"].as_bool().unwrap_or(false);
+
+    json!({
+        "startHeight": start_height,
+        "lastHeight": last_height,
+        "targetHeight": last_height,
+        "lastTime": last_time,
+        "genesis": status["genesis_hash"].as_str().unwrap_or(""),
+        "chainId": status["chain_id"].as_str().unwrap_or(""),
+        "syncing": syncing,
+    })
+}
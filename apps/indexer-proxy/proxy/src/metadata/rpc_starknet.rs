@@ -0,0 +1,104 @@
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use subql_indexer_utils::types::Result;
+use tokio::sync::RwLock;
+
+use super::{first_available_block, project_jsonrpc};
+use crate::metrics::MetricsNetwork;
+use crate::project::Project;
+
+/// endpoint => the genesis block hash, it never changes
+static GENESIS: Lazy<RwLock<HashMap<String, String>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// rpc starknet
+pub async fn metadata(project: &Project, network: MetricsNetwork) -> Result<Value> {
+    let url = &project.endpoint("default", true)?.endpoint;
+
+    let last_height = project_jsonrpc(project, network, url, "starknet_blockNumber", vec![])
+        .await?
+        .as_u64()
+        .unwrap_or(0);
+
+    let chain = project_jsonrpc(project, network, url, "starknet_chainId", vec![]).await?;
+    let chain = decode_chain_id(chain.as_str().unwrap_or(""));
+
+    // false when not syncing, or the sync status with the highest block
+    let syncing = project_jsonrpc(project, network, url, "starknet_syncing", vec![])
+        .await
+        .unwrap_or(Value::Bool(false));
+    let target_height = syncing["highest_block_num"]
+        .as_u64()
+        .unwrap_or(last_height)
+        .max(last_height);
+
+    let last_block = project_jsonrpc(
+        project,
+        network,
+        url,
+        "starknet_getBlockWithTxHashes",
+        vec![json!({ "block_number": last_height })],
+    )
+    .await?;
+
+    // the node can read block 0 had the full history,
+    // otherwise the startHeight is the first block kept by pruned node
+    let genesis = genesis(project, network, url).await;
+    let pruned = genesis.is_none();
+    let start_height = if pruned {
+        first_available_block(url, 0, last_height, |height| async move {
+            block_hash(project, network, url, height).await.is_some()
+        })
+        .await
+    } else {
+        0
+    };
+
+    Ok(json!({
+        "startHeight": start_height,
+        "lastHeight": last_height,
+        "targetHeight": target_height,
+        "pruned": pruned,
+        "lastTime": last_block["timestamp"].as_u64().unwrap_or(0),
+        "genesis": genesis.unwrap_or_default(),
+        "chainId": chain,
+    }))
+}
+
+/// fetch the genesis block once, and cache it
+async fn genesis(project: &Project, network: MetricsNetwork, url: &str) -> Option<String> {
+    if let Some(hash) = GENESIS.read().await.get(url) {
+        return Some(hash.clone());
+    }
+
+    let hash = block_hash(project, network, url, 0).await?;
+    GENESIS.write().await.insert(url.to_owned(), hash.clone());
+    Some(hash)
+}
+
+/// the hash of block, None when the node not keep it
+async fn block_hash(
+    project: &Project,
+    network: MetricsNetwork,
+    url: &str,
+    height: u64,
+) -> Option<String> {
+    let block = project_jsonrpc(
+        project,
+        network,
+        url,
+        "starknet_getBlockWithTxHashes",
+        vec![json!({ "block_number": height })],
+    )
+    .await
+    .ok()?;
+    block["block_hash"].as_str().map(|h| h.to_owned())
+}
+
+/// chain id is a short string encoded as felt, e.g. 0x534e5f4d41494e => SN_MAIN
+fn decode_chain_id(raw: &str) -> String {
+    hex::decode(raw.trim_start_matches("0x"))
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .unwrap_or(raw.to_owned())
+}
@@ -1,70 +1,131 @@
-use chrono::Utc;
 use serde_json::{json, Value};
-use std::time::Instant;
-use subql_indexer_utils::{request::jsonrpc_request, types::Result};
+use subql_indexer_utils::types::Result;
 
-use crate::metrics::{add_metrics_query, MetricsNetwork, MetricsQuery};
+use super::{first_available_block, project_jsonrpc};
+use crate::metrics::MetricsNetwork;
 use crate::project::Project;
 
+/// storage key of `Timestamp::Now`, twox128("Timestamp") ++ twox128("Now")
+const TIMESTAMP_NOW_KEY: &str =
+    "0xf0c365c3cf59d671eb72da0e7a4113c49f1f0515f462cdcf84e0f1d6045dfcbb";
+
 /// rpc substrate
 pub async fn metadata(project: &Project, network: MetricsNetwork) -> Result<Value> {
     let url = &project.endpoint("default", true)?.endpoint;
 
-    let now = Instant::now();
-    let latest_block = jsonrpc_request(url, "chain_getBlock", vec![]).await?;
-    let time = now.elapsed().as_millis() as u64;
-    add_metrics_query(
-        project.id.clone(),
-        Some(time),
-        MetricsQuery::Free,
+    // best block, read the header by hash, the height and hash are same block when re-org
+    let best_hash = project_jsonrpc(project, network, url, "chain_getBlockHash", vec![]).await?;
+    let best_header = project_jsonrpc(
+        project,
         network,
-        true,
-    );
+        url,
+        "chain_getHeader",
+        vec![best_hash.clone()],
+    )
+    .await?;
+    let last_height = hex_u64(&best_header["number"]);
 
-    let last_height = if let Some(item) = latest_block.pointer("/block/header/number") {
-        let raw = item.as_str().unwrap_or("0");
-        let without_prefix = raw.trim_start_matches("0x");
-        i64::from_str_radix(without_prefix, 16).unwrap_or(0)
-    } else {
-        0
-    };
+    // finalized block
+    let finalized_hash =
+        project_jsonrpc(project, network, url, "chain_getFinalizedHead", vec![]).await?;
+    let finalized_header = project_jsonrpc(
+        project,
+        network,
+        url,
+        "chain_getHeader",
+        vec![finalized_hash],
+    )
+    .await?;
+    let finalized_height = hex_u64(&finalized_header["number"]);
+
+    // the time of best block, set by timestamp inherent extrinsic
+    let now = project_jsonrpc(
+        project,
+        network,
+        url,
+        "state_getStorage",
+        vec![json!(TIMESTAMP_NOW_KEY), best_hash],
+    )
+    .await?;
+    let last_time = scale_u64(now.as_str().unwrap_or("")) / 1000;
 
-    let now = Instant::now();
-    let chain = jsonrpc_request(url, "system_chain", vec![])
+    let runtime = project_jsonrpc(project, network, url, "state_getRuntimeVersion", vec![]).await?;
+
+    let chain = project_jsonrpc(project, network, url, "system_chain", vec![])
         .await?
         .as_str()
         .unwrap_or("")
         .to_owned();
-    let time = now.elapsed().as_millis() as u64;
-    add_metrics_query(
-        project.id.clone(),
-        Some(time),
-        MetricsQuery::Free,
-        network,
-        true,
-    );
 
-    let now = Instant::now();
-    let genesis = jsonrpc_request(url, "chain_getBlockHash", vec![json!(0)])
+    let genesis = project_jsonrpc(project, network, url, "chain_getBlockHash", vec![json!(0)])
         .await?
         .as_str()
         .unwrap_or("")
         .to_owned();
-    let time = now.elapsed().as_millis() as u64;
-    add_metrics_query(
-        project.id.clone(),
-        Some(time),
-        MetricsQuery::Free,
-        network,
-        true,
-    );
+
+    // pruned node not keep the old states, the startHeight is the first block has state
+    let pruned = !has_state(project, network, url, 1).await;
+    let start_height = if pruned {
+        first_available_block(url, 1, last_height, |height| {
+            has_state(project, network, url, height)
+        })
+        .await
+    } else {
+        1
+    };
 
     Ok(json!({
-        "startHeight": 1,
+        "startHeight": start_height,
         "lastHeight": last_height,
         "targetHeight": last_height,
-        "lastTime": Utc::now().timestamp(),
+        "finalizedHeight": finalized_height,
+        "pruned": pruned,
+        "lastTime": last_time,
         "genesis": genesis,
         "chainId": chain,
+        "specName": runtime["specName"].as_str().unwrap_or(""),
+        "specVersion": runtime["specVersion"].as_u64().unwrap_or(0),
     }))
 }
+
+/// the node keep the state of block
+async fn has_state(project: &Project, network: MetricsNetwork, url: &str, height: u64) -> bool {
+    let hash = match project_jsonrpc(
+        project,
+        network,
+        url,
+        "chain_getBlockHash",
+        vec![json!(height)],
+    )
+    .await
+    {
+        Ok(hash) if hash.is_string() => hash,
+        _ => return false,
+    };
+    project_jsonrpc(
+        project,
+        network,
+        url,
+        "state_getStorage",
+        vec![json!(TIMESTAMP_NOW_KEY), hash],
+    )
+    .await
+    .map(|v| !v.is_null())
+    .unwrap_or(false)
+}
+
+fn hex_u64(v: &Value) -> u64 {
+    let raw = v.as_str().unwrap_or("0");
+    u64::from_str_radix(raw.trim_start_matches("0x"), 16).unwrap_or(0)
+}
+
+/// decode SCALE encoded u64 (little endian)
+fn scale_u64(raw: &str) -> u64 {
+    let bytes = hex::decode(raw.trim_start_matches("0x")).unwrap_or(vec![]);
+    if bytes.len() < 8 {
+        return 0;
+    }
+    let mut le_bytes = [0u8; 8];
+    le_bytes.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(le_bytes)
+}
@@ -20,7 +20,12 @@ use chrono::prelude::*;
 use once_cell::sync::Lazy;
 use prometheus_client::{
     encoding::{text::encode, EncodeLabelSet},
-    metrics::{counter::Counter, family::Family},
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::Gauge,
+        histogram::{exponential_buckets, Histogram},
+    },
     registry::Registry,
 };
 use serde::Serialize;
@@ -104,15 +109,65 @@ static OWNER_FAILURE: Lazy<Mutex<Family<Labels, Counter>>> =
     Lazy::new(|| Mutex::new(Family::default()));
 static OWNER_TIME: Lazy<Mutex<Family<Labels, Counter>>> =
     Lazy::new(|| Mutex::new(Family::default()));
+static OWNER_LAG: Lazy<Mutex<Family<Labels, Gauge>>> = Lazy::new(|| Mutex::new(Family::default()));
+static OWNER_HEALTHY: Lazy<Mutex<Family<Labels, Gauge>>> =
+    Lazy::new(|| Mutex::new(Family::default()));
+static OWNER_WS: Lazy<Mutex<Family<Labels, Gauge>>> = Lazy::new(|| Mutex::new(Family::default()));
+static OWNER_AI_QUEUE: Lazy<Mutex<Family<Labels, Gauge>>> =
+    Lazy::new(|| Mutex::new(Family::default()));
+static OWNER_AI_WAIT: Lazy<Mutex<Family<Labels, Histogram, fn() -> Histogram>>> =
+    Lazy::new(|| Mutex::new(Family::new_with_constructor(ai_wait_histogram)));
+static OWNER_TELEMETRY_DROPPED: Lazy<Counter> = Lazy::new(Counter::default);
+static OWNER_P2P_PEERS: Lazy<Gauge> = Lazy::new(Gauge::default);
+static OWNER_P2P_NODE: Lazy<Mutex<Family<NodeLabels, Gauge>>> =
+    Lazy::new(|| Mutex::new(Family::default()));
+static OWNER_P2P_PING: Lazy<Mutex<Family<NodeLabels, Gauge>>> =
+    Lazy::new(|| Mutex::new(Family::default()));
+static OWNER_P2P_REQUEST: Lazy<Mutex<Family<RequestLabels, Counter>>> =
+    Lazy::new(|| Mutex::new(Family::default()));
+static OWNER_P2P_ADDRESS: Lazy<Mutex<Family<AddressLabels, Gauge>>> =
+    Lazy::new(|| Mutex::new(Family::default()));
 const FIELD_NAME_SUCCESS: &str = "query_success";
 const FIELD_NAME_FAILURE: &str = "query_failure";
 const FIELD_NAME_TIME: &str = "query_time";
+const FIELD_NAME_LAG: &str = "project_lag";
+const FIELD_NAME_HEALTHY: &str = "project_healthy";
+const FIELD_NAME_WS: &str = "ws_connections";
+const FIELD_NAME_AI_QUEUE: &str = "ai_queue_depth";
+const FIELD_NAME_AI_WAIT: &str = "ai_queue_wait";
+const FIELD_NAME_TELEMETRY_DROPPED: &str = "telemetry_dropped";
+const FIELD_NAME_P2P_PEERS: &str = "p2p_connected_peers";
+const FIELD_NAME_P2P_NODE: &str = "p2p_node_connected";
+const FIELD_NAME_P2P_PING: &str = "p2p_ping_rtt";
+const FIELD_NAME_P2P_REQUEST: &str = "p2p_requests";
+const FIELD_NAME_P2P_ADDRESS: &str = "p2p_addresses";
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
 struct Labels {
     pub deployment: String,
 }
 
+/// node: bootnode, metrics or peer
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct NodeLabels {
+    pub node: String,
+}
+
+/// result: success, failure or timeout
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RequestLabels {
+    pub event: String,
+    pub direction: String,
+    pub result: String,
+}
+
+/// kind: listen or external
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct AddressLabels {
+    pub kind: String,
+    pub address: String,
+}
+
 pub fn listen() {
     tokio::spawn(async {
         loop {
@@ -171,6 +226,8 @@ pub async fn update_metrics_projects(new_deployments: Vec<String>) {
     let os_lock = OWNER_SUCCESS.lock().await;
     let of_lock = OWNER_FAILURE.lock().await;
     let ot_lock = OWNER_TIME.lock().await;
+    let ol_lock = OWNER_LAG.lock().await;
+    let oh_lock = OWNER_HEALTHY.lock().await;
 
     for deployment in timer.keys() {
         old_deployments.push(deployment.clone());
@@ -189,6 +246,8 @@ pub async fn update_metrics_projects(new_deployments: Vec<String>) {
             os_lock.remove(&label);
             of_lock.remove(&label);
             ot_lock.remove(&label);
+            ol_lock.remove(&label);
+            oh_lock.remove(&label);
         }
     }
     for n in new_deployments {
@@ -211,6 +270,122 @@ pub async fn update_metrics_projects(new_deployments: Vec<String>) {
     drop(os_lock);
     drop(of_lock);
     drop(ot_lock);
+    drop(ol_lock);
+    drop(oh_lock);
+}
+
+/// update the project lag blocks & healthy status
+pub async fn update_metrics_lag(deployment: String, lag: u64, healthy: bool) {
+    let label = Labels { deployment };
+
+    let family = OWNER_LAG.lock().await;
+    family.get_or_create(&label).set(lag as i64);
+    drop(family);
+
+    let family = OWNER_HEALTHY.lock().await;
+    family.get_or_create(&label).set(healthy as i64);
+    drop(family);
+}
+
+/// websocket opened (true) or closed (false)
+pub async fn update_metrics_ws(deployment: String, opened: bool) {
+    let label = Labels { deployment };
+
+    let family = OWNER_WS.lock().await;
+    let gauge = family.get_or_create(&label);
+    if opened {
+        gauge.inc();
+    } else {
+        gauge.dec();
+    }
+    drop(family);
+}
+
+/// AI request entered (true) or left (false) the queue
+pub async fn update_metrics_ai_queue(deployment: String, queued: bool) {
+    let label = Labels { deployment };
+
+    let family = OWNER_AI_QUEUE.lock().await;
+    let gauge = family.get_or_create(&label);
+    if queued {
+        gauge.inc();
+    } else {
+        gauge.dec();
+    }
+    drop(family);
+}
+
+/// AI request waited time (ms) in the queue
+pub async fn add_metrics_ai_wait(deployment: String, time: u64) {
+    let label = Labels { deployment };
+
+    let family = OWNER_AI_WAIT.lock().await;
+    family.get_or_create(&label).observe(time as f64);
+    drop(family);
+}
+
+/// the wait time (ms) buckets: 10ms ~ 160s
+fn ai_wait_histogram() -> Histogram {
+    Histogram::new(exponential_buckets(10.0, 4.0, 8))
+}
+
+/// telemetry events dropped before delivered to metrics node
+pub fn add_metrics_telemetry_dropped(count: u64) {
+    OWNER_TELEMETRY_DROPPED.inc_by(count);
+}
+
+/// p2p connected peers, and the bootnode and metrics node connection state
+pub async fn update_metrics_p2p_connections(peers: usize, bootnode: bool, metrics: bool) {
+    OWNER_P2P_PEERS.set(peers as i64);
+
+    let family = OWNER_P2P_NODE.lock().await;
+    for (node, connected) in [("bootnode", bootnode), ("metrics", metrics)] {
+        let label = NodeLabels {
+            node: node.to_owned(),
+        };
+        family.get_or_create(&label).set(connected as i64);
+    }
+    drop(family);
+}
+
+/// p2p ping rtt (ms) of the node
+pub async fn update_metrics_p2p_ping(node: &str, rtt: u64) {
+    let label = NodeLabels {
+        node: node.to_owned(),
+    };
+
+    let family = OWNER_P2P_PING.lock().await;
+    family.get_or_create(&label).set(rtt as i64);
+    drop(family);
+}
+
+/// p2p request-response result of event, direction is inbound or outbound
+pub async fn add_metrics_p2p_request(event: &str, direction: &str, result: &str) {
+    let label = RequestLabels {
+        event: event.to_owned(),
+        direction: direction.to_owned(),
+        result: result.to_owned(),
+    };
+
+    let family = OWNER_P2P_REQUEST.lock().await;
+    family.get_or_create(&label).inc();
+    drop(family);
+}
+
+/// p2p listen or external address added (true) or expired (false)
+pub async fn update_metrics_p2p_address(kind: &str, address: String, added: bool) {
+    let label = AddressLabels {
+        kind: kind.to_owned(),
+        address,
+    };
+
+    let family = OWNER_P2P_ADDRESS.lock().await;
+    if added {
+        family.get_or_create(&label).set(1);
+    } else {
+        family.remove(&label);
+    }
+    drop(family);
 }
 
 pub async fn get_services_version() -> u64 {
@@ -462,6 +637,78 @@ pub async fn get_owner_metrics() -> String {
     registry.register(FIELD_NAME_TIME, "Time of requests", (*family).clone());
     drop(family);
 
+    let family = OWNER_LAG.lock().await;
+    registry.register(FIELD_NAME_LAG, "Blocks behind target", (*family).clone());
+    drop(family);
+
+    let family = OWNER_HEALTHY.lock().await;
+    registry.register(FIELD_NAME_HEALTHY, "Project healthy", (*family).clone());
+    drop(family);
+
+    let family = OWNER_WS.lock().await;
+    registry.register(
+        FIELD_NAME_WS,
+        "Active websocket connections",
+        (*family).clone(),
+    );
+    drop(family);
+
+    let family = OWNER_AI_QUEUE.lock().await;
+    registry.register(
+        FIELD_NAME_AI_QUEUE,
+        "Waiting AI requests in queue",
+        (*family).clone(),
+    );
+    drop(family);
+
+    let family = OWNER_AI_WAIT.lock().await;
+    registry.register(
+        FIELD_NAME_AI_WAIT,
+        "Time (ms) of AI requests waited in queue",
+        (*family).clone(),
+    );
+    drop(family);
+
+    registry.register(
+        FIELD_NAME_TELEMETRY_DROPPED,
+        "Count of dropped telemetry events",
+        (*OWNER_TELEMETRY_DROPPED).clone(),
+    );
+
+    registry.register(
+        FIELD_NAME_P2P_PEERS,
+        "Connected p2p peers",
+        (*OWNER_P2P_PEERS).clone(),
+    );
+
+    let family = OWNER_P2P_NODE.lock().await;
+    registry.register(
+        FIELD_NAME_P2P_NODE,
+        "Bootnode and metrics node connected",
+        (*family).clone(),
+    );
+    drop(family);
+
+    let family = OWNER_P2P_PING.lock().await;
+    registry.register(FIELD_NAME_P2P_PING, "P2P ping rtt (ms)", (*family).clone());
+    drop(family);
+
+    let family = OWNER_P2P_REQUEST.lock().await;
+    registry.register(
+        FIELD_NAME_P2P_REQUEST,
+        "Count of p2p requests by event and result",
+        (*family).clone(),
+    );
+    drop(family);
+
+    let family = OWNER_P2P_ADDRESS.lock().await;
+    registry.register(
+        FIELD_NAME_P2P_ADDRESS,
+        "P2P listen and external addresses",
+        (*family).clone(),
+    );
+    drop(family);
+
     let mut body = String::new();
     let _ = encode(&mut body, &registry);
     body
@@ -1,36 +1,54 @@
 use libp2p::{
+    autonat::{Behaviour as AutonatBehavior, Event as AutonatEvent},
+    dcutr::{Behaviour as DcutrBehavior, Event as DcutrEvent},
+    gossipsub::{Behaviour as GossipsubBehavior, Event as GossipsubEvent},
     identify::{Behaviour as IdentifyBehavior, Event as IdentifyEvent},
     kad::{
         store::MemoryStore as KademliaInMemory, Behaviour as KademliaBehavior,
         Event as KademliaEvent,
     },
     ping::{self, Behaviour as PingBehaviour, Event as PingEvent},
-    request_response::{json::Behaviour as RequestResponseBehavior, Event as RequestResponseEvent},
+    relay::client::{Behaviour as RelayClientBehavior, Event as RelayClientEvent},
+    request_response::{Behaviour as RequestResponseBehavior, Event as RequestResponseEvent},
     swarm::NetworkBehaviour,
 };
 use subql_indexer_utils::p2p::Event;
 
+use crate::mod_libp2p::codec::EventCodec;
+
 #[derive(NetworkBehaviour)]
 #[behaviour(to_swarm = "AgentEvent")]
 pub(crate) struct AgentBehavior {
     pub identify: IdentifyBehavior,
     pub kad: KademliaBehavior<KademliaInMemory>,
-    pub rr: RequestResponseBehavior<Event, Event>,
+    pub rr: RequestResponseBehavior<EventCodec>,
     pub ping: ping::Behaviour,
+    pub gossipsub: GossipsubBehavior,
+    pub autonat: AutonatBehavior,
+    pub relay_client: RelayClientBehavior,
+    pub dcutr: DcutrBehavior,
 }
 
 impl AgentBehavior {
     pub fn new(
         kad: KademliaBehavior<KademliaInMemory>,
         identify: IdentifyBehavior,
-        rr: RequestResponseBehavior<Event, Event>,
+        rr: RequestResponseBehavior<EventCodec>,
         ping: PingBehaviour,
+        gossipsub: GossipsubBehavior,
+        autonat: AutonatBehavior,
+        relay_client: RelayClientBehavior,
+        dcutr: DcutrBehavior,
     ) -> Self {
         Self {
             kad,
             identify,
             rr,
             ping,
+            gossipsub,
+            autonat,
+            relay_client,
+            dcutr,
         }
     }
 }
@@ -41,6 +59,10 @@ pub(crate) enum AgentEvent {
     Kad(KademliaEvent),
     RequestResponse(RequestResponseEvent<Event, Event>),
     Ping(PingEvent),
+    Gossipsub(GossipsubEvent),
+    Autonat(AutonatEvent),
+    RelayClient(RelayClientEvent),
+    Dcutr(DcutrEvent),
 }
 
 impl From<IdentifyEvent> for AgentEvent {
@@ -66,3 +88,27 @@ impl From<PingEvent> for AgentEvent {
         Self::Ping(value)
     }
 }
+
+impl From<GossipsubEvent> for AgentEvent {
+    fn from(value: GossipsubEvent) -> Self {
+        Self::Gossipsub(value)
+    }
+}
+
+impl From<AutonatEvent> for AgentEvent {
+    fn from(value: AutonatEvent) -> Self {
+        Self::Autonat(value)
+    }
+}
+
+impl From<RelayClientEvent> for AgentEvent {
+    fn from(value: RelayClientEvent) -> Self {
+        Self::RelayClient(value)
+    }
+}
+
+impl From<DcutrEvent> for AgentEvent {
+    fn from(value: DcutrEvent) -> Self {
+        Self::Dcutr(value)
+    }
+}
@@ -0,0 +1,96 @@
+use async_trait::async_trait;
+use libp2p::{
+    futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    request_response::Codec,
+    StreamProtocol,
+};
+use std::io;
+use subql_indexer_utils::p2p::Event;
+
+/// the legacy protocol, event is json
+pub const PROTOCOL_V1: StreamProtocol = StreamProtocol::new("/agent/message/1.0.0");
+
+/// the envelope protocol, event is versioned envelope
+pub const PROTOCOL_V2: StreamProtocol = StreamProtocol::new("/agent/message/2.0.0");
+
+/// max size of request: 1MB
+const REQUEST_SIZE_MAXIMUM: u64 = 1024 * 1024;
+
+/// max size of response: 10MB
+const RESPONSE_SIZE_MAXIMUM: u64 = 10 * 1024 * 1024;
+
+/// The request-response codec, negotiated by the protocol,
+/// the legacy peers still use the json event.
+#[derive(Clone, Default)]
+pub struct EventCodec;
+
+fn decode(protocol: &StreamProtocol, data: &[u8]) -> io::Result<Event> {
+    if *protocol == PROTOCOL_V1 {
+        serde_json::from_slice(data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    } else {
+        Event::from_bytes(data)
+    }
+}
+
+fn encode(protocol: &StreamProtocol, event: &Event) -> io::Result<Vec<u8>> {
+    if *protocol == PROTOCOL_V1 {
+        serde_json::to_vec(event).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    } else {
+        Ok(event.to_bytes())
+    }
+}
+
+#[async_trait]
+impl Codec for EventCodec {
+    type Protocol = StreamProtocol;
+    type Request = Event;
+    type Response = Event;
+
+    async fn read_request<T>(&mut self, protocol: &StreamProtocol, io: &mut T) -> io::Result<Event>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut data = Vec::new();
+        io.take(REQUEST_SIZE_MAXIMUM).read_to_end(&mut data).await?;
+        decode(protocol, &data)
+    }
+
+    async fn read_response<T>(&mut self, protocol: &StreamProtocol, io: &mut T) -> io::Result<Event>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut data = Vec::new();
+        io.take(RESPONSE_SIZE_MAXIMUM)
+            .read_to_end(&mut data)
+            .await?;
+        decode(protocol, &data)
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        protocol: &StreamProtocol,
+        io: &mut T,
+        req: Event,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let data = encode(protocol, &req)?;
+        io.write_all(&data).await?;
+        io.close().await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        protocol: &StreamProtocol,
+        io: &mut T,
+        res: Event,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let data = encode(protocol, &res)?;
+        io.write_all(&data).await?;
+        io.close().await
+    }
+}
@@ -0,0 +1,223 @@
+use chrono::prelude::*;
+use ethers::{
+    abi::{encode, Address, Tokenizable},
+    signers::Signer,
+    utils::keccak256,
+};
+use futures_util::future::join_all;
+use libp2p::{kad::RecordKey, PeerId};
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+use std::{collections::HashMap, time::Duration};
+use subql_indexer_utils::{
+    error::Error,
+    payg::{convert_sign_to_string, convert_string_to_sign},
+    types::Result,
+};
+use tokio::sync::{oneshot, RwLock};
+
+use crate::account::ACCOUNT;
+use crate::contracts::get_indexer_controller;
+use crate::mod_libp2p::{
+    local_peer_id,
+    network::{DhtCommand, EventLoop},
+};
+use crate::payg::merket_price;
+use crate::primitives::{
+    P2P_ATTESTATION_CACHE_TIME, P2P_ATTESTATION_MAX_AGE, P2P_GOSSIP_MAX_AGE, P2P_REQUEST_TIMEOUT,
+};
+use crate::project::{get_project, list_projects};
+
+/// peer => the verified (indexer, controller) and the verified time
+static ATTESTATIONS: Lazy<RwLock<HashMap<PeerId, (Address, Address, i64)>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// the DHT key of the deployment providers
+pub fn provider_key(deployment: &str) -> RecordKey {
+    RecordKey::new(&format!("/subquery/deployment/{}", deployment))
+}
+
+/// the DHT key of the provider's signed price record
+pub fn price_key(deployment: &str, peer: &PeerId) -> RecordKey {
+    RecordKey::new(&format!("/subquery/price/{}/{}", deployment, peer))
+}
+
+/// the DHT key of the peer attestation
+pub fn attestation_key(peer: &PeerId) -> RecordKey {
+    RecordKey::new(&format!("/subquery/attestation/{}", peer))
+}
+
+/// the signed price (from payg-price) and public endpoints of deployment
+async fn price_record(deployment: &str) -> Result<Vec<u8>> {
+    let project = get_project(deployment).await?;
+    let price = merket_price(Some(deployment.to_owned())).await?;
+    let endpoints: Vec<&String> = project
+        .endpoints
+        .iter()
+        .filter(|(_, e)| !e.is_internal)
+        .map(|(k, _)| k)
+        .collect();
+
+    let record = json!({
+        "deployment": deployment,
+        "price": price,
+        "endpoints": endpoints,
+    });
+    Ok(serde_json::to_vec(&record).unwrap_or_default())
+}
+
+/// start providing the new deployments, and stop the removed deployments
+pub async fn provide_deployments(provides: Vec<String>, removes: Vec<String>) {
+    for deployment in removes {
+        EventLoop::send_dht_command(DhtCommand::StopProvide(deployment)).await;
+    }
+
+    for deployment in provides {
+        match price_record(&deployment).await {
+            Ok(record) => {
+                EventLoop::send_dht_command(DhtCommand::Provide(deployment, record)).await;
+            }
+            Err(err) => warn!("DHT provide {} failure: {:?}", deployment, err),
+        }
+    }
+}
+
+/// republish all the deployments, the signed price will be expired
+pub async fn provide_all_deployments() {
+    let deployments = list_projects().await.into_iter().map(|p| p.id).collect();
+    provide_deployments(deployments, vec![]).await;
+}
+
+fn attestation_hash(peer: &str, indexer: Address, timestamp: i64) -> [u8; 32] {
+    let payload = encode(&[
+        peer.to_owned().into_token(),
+        indexer.into_token(),
+        timestamp.into_token(),
+    ]);
+    keccak256(payload)
+}
+
+/// the attestation binding the peer id to indexer, signed by the controller
+pub async fn attestation() -> Option<Value> {
+    let peer = local_peer_id()?.to_base58();
+
+    let lock = ACCOUNT.read().await;
+    let indexer = lock.indexer;
+    let controller = lock.controller.clone();
+    drop(lock);
+    if indexer == Address::default() {
+        return None;
+    }
+
+    let timestamp = Utc::now().timestamp();
+    let hash = attestation_hash(&peer, indexer, timestamp);
+    let sign = controller.sign_message(hash).await.ok()?;
+
+    Some(json!({
+        "peer": peer,
+        "indexer": format!("{:?}", indexer),
+        "controller": format!("{:?}", controller.address()),
+        "timestamp": timestamp,
+        "signature": convert_sign_to_string(&sign),
+    }))
+}
+
+/// publish the attestation to DHT, when controller changed or the record expired
+pub async fn publish_attestation() {
+    if let (Some(peer), Some(value)) = (local_peer_id(), attestation().await) {
+        let record = serde_json::to_vec(&value).unwrap_or_default();
+        EventLoop::send_dht_command(DhtCommand::PutRecord(attestation_key(&peer), record)).await;
+    }
+}
+
+/// verify the attestation of peer, return the (indexer, controller).
+/// reject the stale attestation, and the controller not registered by indexer in contracts.
+pub async fn verify_attestation(peer: &PeerId, value: &Value) -> Option<(Address, Address)> {
+    if value["peer"].as_str()? != peer.to_base58() {
+        return None;
+    }
+    let indexer: Address = value["indexer"].as_str()?.parse().ok()?;
+    let controller: Address = value["controller"].as_str()?.parse().ok()?;
+    let timestamp = value["timestamp"].as_i64()?;
+    let sign = convert_string_to_sign(value["signature"].as_str()?);
+
+    // allow a little clock drift of the future timestamp
+    let now = Utc::now().timestamp();
+    if now - timestamp > P2P_ATTESTATION_MAX_AGE || timestamp - now > P2P_GOSSIP_MAX_AGE {
+        return None;
+    }
+
+    let hash = attestation_hash(&peer.to_base58(), indexer, timestamp);
+    if sign.recover(&hash[..]).ok()? != controller {
+        return None;
+    }
+
+    match get_indexer_controller(indexer).await {
+        Ok(registered) if registered == controller => Some((indexer, controller)),
+        Ok(_) => None,
+        Err(err) => {
+            warn!("Attestation of {} cannot check controller: {:?}", peer, err);
+            None
+        }
+    }
+}
+
+/// the (indexer, controller) of peer, by the attestation in DHT
+pub async fn peer_indexer(peer: &PeerId) -> Option<(Address, Address)> {
+    let now = Utc::now().timestamp();
+    if let Some((indexer, controller, verified_at)) = ATTESTATIONS.read().await.get(peer) {
+        if now - verified_at < P2P_ATTESTATION_CACHE_TIME {
+            return Some((*indexer, *controller));
+        }
+    }
+
+    let value = get_record(attestation_key(peer)).await;
+    let (indexer, controller) = verify_attestation(peer, &value).await?;
+
+    let mut lock = ATTESTATIONS.write().await;
+    lock.retain(|_, (_, _, at)| now - *at < P2P_ATTESTATION_CACHE_TIME);
+    lock.insert(*peer, (indexer, controller, now));
+    drop(lock);
+    Some((indexer, controller))
+}
+
+/// get the json record value from DHT
+async fn get_record(key: RecordKey) -> Value {
+    let (sender, receiver) = oneshot::channel();
+    if !EventLoop::send_dht_command(DhtCommand::GetRecord(key, sender)).await {
+        return Value::Null;
+    }
+    let timeout = Duration::from_secs(P2P_REQUEST_TIMEOUT);
+    match tokio::time::timeout(timeout, receiver).await {
+        Ok(Ok(Some(value))) => serde_json::from_slice(&value).unwrap_or(Value::Null),
+        _ => Value::Null,
+    }
+}
+
+/// find the providers of deployment, with their price records and attestations
+pub async fn find_providers(deployment: &str) -> Result<Value> {
+    let timeout = Duration::from_secs(P2P_REQUEST_TIMEOUT);
+
+    let (sender, receiver) = oneshot::channel();
+    if !EventLoop::send_dht_command(DhtCommand::FindProviders(deployment.to_owned(), sender)).await
+    {
+        return Err(Error::ServiceException(1208));
+    }
+    let peers = tokio::time::timeout(timeout, receiver)
+        .await
+        .map_err(|_| Error::ServiceException(1209))?
+        .map_err(|_| Error::ServiceException(1209))?;
+
+    let providers = join_all(peers.iter().map(|peer| async move {
+        let record = get_record(price_key(deployment, peer)).await;
+        let indexer = peer_indexer(peer).await.map(|(i, _)| format!("{:?}", i));
+        json!({
+            "peer": peer.to_base58(),
+            "indexer": indexer,
+            "record": record,
+        })
+    }))
+    .await;
+
+    Ok(json!(providers))
+}
@@ -0,0 +1,205 @@
+use chrono::prelude::*;
+use ethers::{
+    abi::{encode, Address, Tokenizable},
+    signers::Signer,
+    types::U256,
+    utils::keccak256,
+};
+use libp2p::{
+    gossipsub::{IdentTopic, TopicHash},
+    PeerId,
+};
+use serde_json::{json, Value};
+use subql_indexer_utils::payg::{
+    convert_sign_to_string, convert_string_to_sign, price_recover, price_tag_recover,
+};
+
+use crate::account::{indexer_healthy, ACCOUNT};
+use crate::cli::COMMAND;
+use crate::mod_libp2p::{dht::peer_indexer, network::EventLoop};
+use crate::payg::merket_price;
+use crate::primitives::P2P_GOSSIP_MAX_AGE;
+use crate::project::list_projects;
+
+/// the topic of signed price announcements in this network
+pub fn price_topic() -> IdentTopic {
+    IdentTopic::new(format!("/subquery/{}/price", COMMAND.network))
+}
+
+/// the topic of indexer healthy announcements in this network
+pub fn healthy_topic() -> IdentTopic {
+    IdentTopic::new(format!("/subquery/{}/healthy", COMMAND.network))
+}
+
+/// announce the signed prices of deployments (same as payg-price)
+pub async fn announce_prices(deployments: Vec<String>) {
+    for deployment in deployments {
+        match merket_price(Some(deployment.clone())).await {
+            Ok(price) => {
+                // only announce the payg opened deployments
+                let opened = price["deployments"]
+                    .as_array()
+                    .map(|v| !v.is_empty())
+                    .unwrap_or(false);
+                if !opened {
+                    continue;
+                }
+                let data = serde_json::to_vec(&price).unwrap_or_default();
+                EventLoop::publish(price_topic(), data).await;
+            }
+            Err(err) => warn!("Gossip price {} failure: {:?}", deployment, err),
+        }
+    }
+}
+
+/// re-announce all the prices for the new subscribers, the signed price will be expired
+pub async fn announce_all_prices() {
+    let deployments = list_projects().await.into_iter().map(|p| p.id).collect();
+    announce_prices(deployments).await;
+}
+
+/// the healthy announcement, signed by the controller
+pub async fn healthy_announcement() -> Vec<u8> {
+    let healthy = indexer_healthy().await;
+    let data = serde_json::to_string(&healthy).unwrap_or("".to_owned());
+
+    let lock = ACCOUNT.read().await;
+    let controller = lock.controller.clone();
+    drop(lock);
+
+    let timestamp = Utc::now().timestamp();
+    let hash = healthy_hash(&data, timestamp);
+    let signature = match controller.sign_message(hash).await {
+        Ok(sign) => convert_sign_to_string(&sign),
+        Err(_) => return vec![],
+    };
+
+    serde_json::to_vec(&json!({
+        "healthy": data,
+        "timestamp": timestamp,
+        "signature": signature,
+    }))
+    .unwrap_or_default()
+}
+
+fn healthy_hash(data: &str, timestamp: i64) -> [u8; 32] {
+    let payload = encode(&[data.to_owned().into_token(), timestamp.into_token()]);
+    keccak256(payload)
+}
+
+/// validate the announcement, reject the unsigned or stale messages,
+/// and the messages which signer is not the attested controller of author peer
+pub async fn validate(source: Option<PeerId>, topic: &TopicHash, data: &[u8]) -> bool {
+    let value: Value = match serde_json::from_slice(data) {
+        Ok(v) => v,
+        Err(_) => return false,
+    };
+    let now = Utc::now().timestamp();
+
+    let signer = if *topic == price_topic().hash() {
+        validate_price(&value, now)
+    } else if *topic == healthy_topic().hash() {
+        validate_healthy(&value, now)
+    } else {
+        None
+    };
+    let (Some(signer), Some(source)) = (signer, source) else {
+        return false;
+    };
+
+    peer_indexer(&source).await == Some(signer)
+}
+
+/// the (indexer, controller) of the announcement
+fn announcer(value: &Value) -> Option<(Address, Address)> {
+    let indexer = value["indexer"].as_str()?.parse().ok()?;
+    let controller = value["controller"].as_str()?.parse().ok()?;
+    Some((indexer, controller))
+}
+
+/// the price is signed by the controller, return the (indexer, controller)
+fn validate_price(value: &Value, now: i64) -> Option<(Address, Address)> {
+    let (indexer, controller) = announcer(value)?;
+    let deployments = match value["deployments"].as_array() {
+        Some(d) if !d.is_empty() => d,
+        _ => return None,
+    };
+
+    // (deployment, price, expiration, token, expired, signature, ai), the ai is
+    // {outputPrice, outputSignature, models} only for AI project
+    let valid = deployments.iter().all(|item| {
+        let price = item[1].as_str().and_then(|s| U256::from_dec_str(s).ok());
+        let token = item[3].as_str().and_then(|s| s.parse::<Address>().ok());
+        let expired = item[4].as_i64();
+        let sign = item[5].as_str().map(convert_string_to_sign);
+        match (price, token, expired, sign) {
+            (Some(price), Some(token), Some(expired), Some(sign)) => {
+                expired > now
+                    && price_recover(price, token, expired, sign).ok() == Some(controller)
+                    && validate_ai_prices(item, token, expired, controller)
+            }
+            _ => false,
+        }
+    });
+
+    if valid {
+        Some((indexer, controller))
+    } else {
+        None
+    }
+}
+
+/// the output and model prices of AI project are signed with the tags
+fn validate_ai_prices(item: &Value, token: Address, expired: i64, controller: Address) -> bool {
+    let check = |tag: &str, price: &Value, sign: &Value| -> bool {
+        let price = price.as_str().and_then(|s| U256::from_dec_str(s).ok());
+        let sign = sign.as_str().map(convert_string_to_sign);
+        match (price, sign) {
+            (Some(price), Some(sign)) => {
+                price_tag_recover(tag, price, token, expired, sign).ok() == Some(controller)
+            }
+            _ => false,
+        }
+    };
+
+    let Some(ai) = item.get(6) else {
+        return true;
+    };
+    if !check("output", &ai["outputPrice"], &ai["outputSignature"]) {
+        return false;
+    }
+    match ai["models"].as_object() {
+        Some(models) => models.iter().all(|(model, p)| {
+            check(
+                &format!("model:{}:input", model),
+                &p["inputPrice"],
+                &p["inputSignature"],
+            ) && check(
+                &format!("model:{}:output", model),
+                &p["outputPrice"],
+                &p["outputSignature"],
+            )
+        }),
+        None => false,
+    }
+}
+
+/// the healthy is signed by the controller, return the (indexer, controller)
+fn validate_healthy(value: &Value, now: i64) -> Option<(Address, Address)> {
+    let data = value["healthy"].as_str()?;
+    let timestamp = value["timestamp"].as_i64()?;
+    let signature = value["signature"].as_str()?;
+    if (now - timestamp).abs() > P2P_GOSSIP_MAX_AGE {
+        return None;
+    }
+
+    let healthy: Value = serde_json::from_str(data).ok()?;
+    let (indexer, controller) = announcer(&healthy)?;
+    let sign = convert_string_to_sign(signature);
+    let hash = healthy_hash(data, timestamp);
+    if sign.recover(&hash[..]).ok()? == controller {
+        Some((indexer, controller))
+    } else {
+        None
+    }
+}
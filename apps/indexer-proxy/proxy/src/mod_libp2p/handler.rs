@@ -0,0 +1,335 @@
+use base64::{engine::general_purpose, Engine as _};
+use serde_json::{json, Value};
+use subql_indexer_utils::{
+    error::Error,
+    p2p::Event,
+    payg::{MultipleQueryState, QueryState},
+    tools::deployment_cid,
+    types::Result,
+};
+
+use crate::auth::{jwt_deployment, verify_auth, verify_auth_limit};
+use crate::metadata::cached_metadata;
+use crate::metrics::{MetricsNetwork, MetricsQuery};
+use crate::payg::{
+    fetch_channel_cache, merket_price, open_state, query_multiple_state, query_single_state,
+};
+use crate::poi::project_poi;
+use crate::project::{get_project, Project};
+
+/// handle the inbound request from other peers, return the response event
+pub async fn handle_request(event: Event) -> Option<Event> {
+    match event {
+        Event::ProjectMetadata(deployment, height) => {
+            let res = match get_project(&deployment).await {
+                Ok(project) => match height {
+                    Some(height) => project_poi(&project, height, MetricsNetwork::P2P).await,
+                    None => cached_metadata(&project).await,
+                },
+                Err(err) => Err(err),
+            };
+            let data = res.unwrap_or_else(|err| err.to_json());
+            Some(Event::ProjectMetadataRes(data.to_string()))
+        }
+        Event::PaygPrice(project) => {
+            let data = merket_price(project)
+                .await
+                .unwrap_or_else(|err| err.to_json());
+            Some(Event::PaygPriceRes(data.to_string()))
+        }
+        Event::PaygOpen(uid, state) => {
+            let res = match serde_json::from_str::<Value>(&state) {
+                Ok(state) => open_state(&state).await,
+                Err(_) => Err(Error::Serialize(1142)),
+            };
+            let data = res.unwrap_or_else(|err| err.to_json());
+            Some(Event::PaygOpenRes(uid, data.to_string()))
+        }
+        Event::PaygQuery(uid, query, ep_name, state) => {
+            let data = payg_query(query, ep_name, state)
+                .await
+                .unwrap_or_else(|err| err.to_json());
+            Some(Event::PaygQueryRes(uid, data.to_string()))
+        }
+        Event::CloseAgreementLimit(uid, token) => {
+            let res = verify_auth_limit(&bearer(&token)).await.map(
+                |(daily_limit, daily_used, rate_limit, rate_used)| {
+                    json!({
+                        "daily_limit": daily_limit,
+                        "daily_used": daily_used,
+                        "rate_limit": rate_limit,
+                        "rate_used": rate_used,
+                    })
+                },
+            );
+            let data = res.unwrap_or_else(|err| err.to_json());
+            Some(Event::CloseAgreementLimitRes(uid, data.to_string()))
+        }
+        Event::CloseAgreementQuery(uid, token, query, ep_name) => {
+            let data = agreement_query(token, query, ep_name)
+                .await
+                .unwrap_or_else(|err| err.to_json());
+            Some(Event::CloseAgreementQueryRes(uid, data.to_string()))
+        }
+        _ => None,
+    }
+}
+
+/// the auth token of close agreement, same as http `Authorization` header
+fn bearer(token: &str) -> String {
+    if token.starts_with("Bearer ") {
+        token.to_owned()
+    } else {
+        format!("Bearer {}", token)
+    }
+}
+
+/// the http endpoint of project, websocket and AI stream not supported in p2p
+fn p2p_endpoint(project: &Project, ep_name: Option<String>) -> Result<String> {
+    let ep_name = ep_name.unwrap_or("default".to_owned());
+    let endpoint = project.endpoint(&ep_name, true)?;
+    if endpoint.is_ws {
+        return Err(Error::WebSocket(1315));
+    }
+    if project.is_ai_project() {
+        return Err(Error::InvalidRequest(1084));
+    }
+    Ok(endpoint.endpoint.clone())
+}
+
+/// query by state channel, the project is the deployment of channel.
+/// the state is multiple state (base64 bytes) or single state (base64 json)
+async fn payg_query(query: String, ep_name: Option<String>, state: String) -> Result<Value> {
+    let (data, signature, state, _limit) = match MultipleQueryState::from_bs64(state.clone()) {
+        Ok(state) => {
+            let (state_cache, _) = fetch_channel_cache(state.channel_id).await?;
+            let project_id = deployment_cid(&state_cache.deployment);
+            let endpoint = p2p_endpoint(&get_project(&project_id).await?, ep_name)?;
+            query_multiple_state(
+                &project_id,
+                query,
+                endpoint,
+                state,
+                MetricsNetwork::P2P,
+                false,
+            )
+            .await?
+        }
+        Err(_) => {
+            let state = QueryState::from_bs64_old1(state)?;
+            let (state_cache, _) = fetch_channel_cache(state.channel_id).await?;
+            let project_id = deployment_cid(&state_cache.deployment);
+            let endpoint = p2p_endpoint(&get_project(&project_id).await?, ep_name)?;
+            query_single_state(
+                &project_id,
+                query,
+                endpoint,
+                state,
+                MetricsNetwork::P2P,
+                false,
+            )
+            .await?
+        }
+    };
+
+    Ok(json!({
+        "result": general_purpose::STANDARD.encode(&data),
+        "signature": signature,
+        "state": state,
+    }))
+}
+
+/// query by close agreement, the token is created by `/token`
+async fn agreement_query(token: String, query: String, ep_name: Option<String>) -> Result<Value> {
+    let auth = bearer(&token);
+    let deployment = jwt_deployment(&auth)?;
+    let project = get_project(&deployment).await?;
+    let ((units, _), _) = project.compute_query_method(&query)?;
+    if verify_auth(&auth, units).await? != deployment {
+        return Err(Error::AuthVerify(1004));
+    }
+    let endpoint = p2p_endpoint(&project, ep_name)?;
+    let (data, signature, _limit) = project
+        .check_query(
+            query,
+            endpoint,
+            MetricsQuery::CloseAgreement,
+            MetricsNetwork::P2P,
+            false,
+            false,
+            None,
+        )
+        .await?;
+
+    Ok(json!({
+        "result": general_purpose::STANDARD.encode(&data),
+        "signature": signature,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mod_libp2p::codec::{EventCodec, PROTOCOL_V1, PROTOCOL_V2};
+    use libp2p::{
+        futures::StreamExt,
+        noise,
+        request_response::{self, ProtocolSupport},
+        swarm::SwarmEvent,
+        tcp, yamux, Multiaddr, PeerId, StreamProtocol, Swarm, SwarmBuilder,
+    };
+    use std::time::Duration;
+
+    type EventSwarm = Swarm<request_response::Behaviour<EventCodec>>;
+
+    fn new_swarm(protocols: Vec<StreamProtocol>) -> EventSwarm {
+        SwarmBuilder::with_new_identity()
+            .with_tokio()
+            .with_tcp(
+                tcp::Config::default(),
+                noise::Config::new,
+                yamux::Config::default,
+            )
+            .unwrap()
+            .with_behaviour(|_| {
+                request_response::Behaviour::with_codec(
+                    EventCodec,
+                    protocols.into_iter().map(|p| (p, ProtocolSupport::Full)),
+                    request_response::Config::default(),
+                )
+            })
+            .unwrap()
+            .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
+            .build()
+    }
+
+    /// start a peer which handle the requests by handler, return the peer & address
+    async fn start_responder() -> (PeerId, Multiaddr) {
+        let mut swarm = new_swarm(vec![PROTOCOL_V2, PROTOCOL_V1]);
+        swarm
+            .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .unwrap();
+        let addr = loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = swarm.select_next_some().await {
+                break address;
+            }
+        };
+        let peer = *swarm.local_peer_id();
+
+        tokio::spawn(async move {
+            loop {
+                if let SwarmEvent::Behaviour(request_response::Event::Message {
+                    message:
+                        request_response::Message::Request {
+                            request, channel, ..
+                        },
+                    ..
+                }) = swarm.select_next_some().await
+                {
+                    if let Some(response) = handle_request(request).await {
+                        let _ = swarm.behaviour_mut().send_response(channel, response);
+                    }
+                }
+            }
+        });
+
+        (peer, addr)
+    }
+
+    async fn round_trip(swarm: &mut EventSwarm, peer: PeerId, event: Event) -> Event {
+        swarm.behaviour_mut().send_request(&peer, event);
+        loop {
+            match swarm.select_next_some().await {
+                SwarmEvent::Behaviour(request_response::Event::Message {
+                    message: request_response::Message::Response { response, .. },
+                    ..
+                }) => return response,
+                SwarmEvent::Behaviour(request_response::Event::OutboundFailure {
+                    error, ..
+                }) => panic!("request failure: {:?}", error),
+                _ => {}
+            }
+        }
+    }
+
+    fn error_code(data: &str) -> i64 {
+        let data: Value = serde_json::from_str(data).unwrap();
+        data["code"].as_i64().unwrap_or(0)
+    }
+
+    #[tokio::test]
+    async fn request_round_trip() {
+        let (peer, addr) = start_responder().await;
+        let mut swarm = new_swarm(vec![PROTOCOL_V2]);
+        swarm.add_peer_address(peer, addr);
+
+        let res = round_trip(
+            &mut swarm,
+            peer,
+            Event::PaygPrice(Some("QmNone".to_owned())),
+        )
+        .await;
+        let Event::PaygPriceRes(data) = res else {
+            panic!("unexpected response: {:?}", res);
+        };
+        let data: Value = serde_json::from_str(&data).unwrap();
+        assert_eq!(data["deployments"], json!([]));
+
+        let res = round_trip(&mut swarm, peer, Event::PaygOpen(1, "{".to_owned())).await;
+        let Event::PaygOpenRes(1, data) = res else {
+            panic!("unexpected response: {:?}", res);
+        };
+        assert_eq!(error_code(&data), 1142);
+
+        let event = Event::PaygQuery(2, "{}".to_owned(), None, "invalid".to_owned());
+        let res = round_trip(&mut swarm, peer, event).await;
+        let Event::PaygQueryRes(2, data) = res else {
+            panic!("unexpected response: {:?}", res);
+        };
+        assert_ne!(error_code(&data), 0);
+
+        let event = Event::CloseAgreementLimit(3, "invalid".to_owned());
+        let res = round_trip(&mut swarm, peer, event).await;
+        let Event::CloseAgreementLimitRes(3, data) = res else {
+            panic!("unexpected response: {:?}", res);
+        };
+        assert_eq!(error_code(&data), 1005);
+
+        let event = Event::CloseAgreementQuery(4, "invalid".to_owned(), "{}".to_owned(), None);
+        let res = round_trip(&mut swarm, peer, event).await;
+        let Event::CloseAgreementQueryRes(4, data) = res else {
+            panic!("unexpected response: {:?}", res);
+        };
+        assert_eq!(error_code(&data), 1005);
+    }
+
+    #[tokio::test]
+    async fn legacy_request_round_trip() {
+        let (peer, addr) = start_responder().await;
+        let mut swarm = new_swarm(vec![PROTOCOL_V1]);
+        swarm.add_peer_address(peer, addr);
+
+        let res = round_trip(&mut swarm, peer, Event::PaygOpen(1, "{".to_owned())).await;
+        let Event::PaygOpenRes(1, data) = res else {
+            panic!("unexpected response: {:?}", res);
+        };
+        assert_eq!(error_code(&data), 1142);
+    }
+
+    #[tokio::test]
+    async fn unknown_event_skipped() {
+        // the envelope of newer type from the future version
+        let mut bytes = Event::PaygPrice(None).to_bytes();
+        bytes[3..5].copy_from_slice(&100u16.to_be_bytes());
+        let event = Event::from_bytes(&bytes).unwrap();
+        assert!(matches!(event, Event::Unknown(100)));
+        assert!(handle_request(event).await.is_none());
+
+        // the known type keep decoding
+        let bytes = Event::PaygPrice(None).to_bytes();
+        assert!(matches!(
+            Event::from_bytes(&bytes),
+            Ok(Event::PaygPrice(None))
+        ));
+    }
+}
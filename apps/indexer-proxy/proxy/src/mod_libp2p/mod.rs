@@ -1,15 +1,36 @@
-use std::time::Duration;
-
-use libp2p::identity::{self, Keypair};
+use libp2p::{
+    identity::{self, Keypair},
+    PeerId,
+};
+use once_cell::sync::OnceCell;
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
 use tokio::time::sleep;
 
+use crate::cli::COMMAND;
 use crate::mod_libp2p::network::EventLoop;
 
 pub mod behavior;
+pub mod codec;
+pub mod dht;
+pub mod gossip;
+pub mod handler;
 pub mod network;
+pub mod outbox;
+
+static NODE_KEY: OnceCell<Keypair> = OnceCell::new();
+
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/// start the p2p network once, the identity is the persistent node key
+pub async fn start_libp2p_process() {
+    if COMMAND.no_p2p || STARTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
 
-pub async fn start_libp2p_process(controller_sk: &str) {
-    let local_key = make_libp2p_keypair(controller_sk).await;
+    let local_key = node_keypair().await;
     tokio::spawn(async move {
         let mut backoff = Duration::from_secs(1);
         loop {
@@ -26,23 +47,64 @@ pub async fn start_libp2p_process(controller_sk: &str) {
     });
 }
 
-pub async fn make_libp2p_keypair(controller_sk: &str) -> Keypair {
-    if let Ok(private_key_bytes) = hex::decode(&controller_sk) {
-        if let Ok(secret_key) = identity::secp256k1::SecretKey::try_from_bytes(private_key_bytes) {
-            identity::secp256k1::Keypair::from(secret_key).into()
-        } else {
-            make_fake_libp2p_keypair().await
+/// the local peer id, when the node key loaded
+pub fn local_peer_id() -> Option<PeerId> {
+    NODE_KEY.get().map(|key| key.public().to_peer_id())
+}
+
+/// the node key, from `--p2p-key` or the key file, generate and save when missing
+pub async fn node_keypair() -> Keypair {
+    if let Some(key) = NODE_KEY.get() {
+        return key.clone();
+    }
+
+    let key = load_node_keypair().await;
+    NODE_KEY.get_or_init(|| key).clone()
+}
+
+async fn load_node_keypair() -> Keypair {
+    if !COMMAND.p2p_key.is_empty() {
+        if let Some(key) = parse_keypair(&COMMAND.p2p_key) {
+            return key;
+        }
+        warn!("Invalid p2p key, use the key file");
+    }
+
+    let path = COMMAND.p2p_key_path();
+    info!("P2P node key file: {:?}", path);
+    if let Ok(data) = tokio::fs::read_to_string(&path).await {
+        if let Some(key) = parse_keypair(&data) {
+            return key;
+        }
+        warn!("Invalid p2p key file {:?}, generate new key", path);
+    }
+
+    let key = identity::secp256k1::Keypair::generate();
+    if let Some(dir) = path.parent() {
+        let _ = tokio::fs::create_dir_all(dir).await;
+    }
+    match tokio::fs::write(&path, hex::encode(key.secret().to_bytes())).await {
+        Ok(_) => {
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                let perm = std::fs::Permissions::from_mode(0o600);
+                let _ = tokio::fs::set_permissions(&path, perm).await;
+            }
         }
-    } else {
-        make_fake_libp2p_keypair().await
+        Err(err) => warn!(
+            "Save p2p key to {:?} failure: {:?}, peer id will change after restart",
+            path, err
+        ),
     }
+
+    key.into()
 }
 
-pub async fn make_fake_libp2p_keypair() -> Keypair {
-    let private_key_bytes =
-        hex::decode("0000000000000000000000000000000000000000000000000000000000000001").unwrap();
-    let secret_key = identity::secp256k1::SecretKey::try_from_bytes(private_key_bytes).unwrap();
-    identity::secp256k1::Keypair::from(secret_key).into()
+fn parse_keypair(sk: &str) -> Option<Keypair> {
+    let bytes = hex::decode(sk.trim().trim_start_matches("0x")).ok()?;
+    let secret_key = identity::secp256k1::SecretKey::try_from_bytes(bytes).ok()?;
+    Some(identity::secp256k1::Keypair::from(secret_key).into())
 }
 
 pub async fn monitor_libp2p_connection(
@@ -1,87 +1,201 @@
 use crate::{
     account::{get_indexer, indexer_healthy},
     cli::COMMAND,
-    metrics::get_timer_metrics,
-    mod_libp2p::behavior::{AgentBehavior, AgentEvent},
-    primitives::{P2P_BROADCAST_HEALTHY_TIME, P2P_METRICS_TIME},
+    metrics::{
+        add_metrics_p2p_request, add_metrics_telemetry_dropped, get_timer_metrics,
+        update_metrics_p2p_address, update_metrics_p2p_connections, update_metrics_p2p_ping,
+    },
+    mod_libp2p::{
+        behavior::{AgentBehavior, AgentEvent},
+        codec::{EventCodec, PROTOCOL_V1, PROTOCOL_V2},
+        dht::{price_key, provide_all_deployments, provider_key, publish_attestation},
+        gossip::{announce_all_prices, healthy_announcement, healthy_topic, price_topic, validate},
+        handler::handle_request,
+        outbox,
+    },
+    primitives::{
+        P2P_BROADCAST_HEALTHY_TIME, P2P_IDLE_CONNECTION_TIME, P2P_METRICS_TIME, P2P_OUTBOX_TIMEOUT,
+        P2P_PROVIDE_TIME, P2P_REQUEST_TIMEOUT,
+    },
 };
 use futures_util::StreamExt;
 use libp2p::{
-    core::ConnectedPoint,
+    autonat::{
+        Behaviour as AutonatBehavior, Config as AutonatConfig, Event as AutonatEvent, NatStatus,
+    },
+    core::{transport::ListenerId, ConnectedPoint},
+    dcutr::{Behaviour as DcutrBehavior, Event as DcutrEvent},
+    gossipsub::{
+        Behaviour as GossipsubBehavior, ConfigBuilder as GossipsubConfigBuilder,
+        Event as GossipsubEvent, IdentTopic, MessageAcceptance, MessageAuthenticity, MessageId,
+        ValidationMode,
+    },
     identify::{Behaviour as IdentifyBehavior, Config as IdentifyConfig, Event as IdentifyEvent},
     identity::Keypair,
     kad::{
         store::MemoryStore as KadInMemory, Behaviour as KadBehavior, Config as KadConfig,
-        Event as KademliaEvent,
+        Event as KademliaEvent, GetProvidersOk, GetRecordOk, QueryId, QueryResult, Quorum, Record,
+        RecordKey,
     },
     multiaddr::Protocol,
     noise,
     ping::{self, Event as PingEvent},
+    relay::client::Event as RelayClientEvent,
     request_response::{
-        json::Behaviour as RequestResponseBehavior, Config as RequestResponseConfig,
-        Event as RequestResponseEvent, ProtocolSupport as RequestResponseProtocolSupport,
+        Behaviour as RequestResponseBehavior, Config as RequestResponseConfig,
+        Event as RequestResponseEvent, InboundFailure, InboundRequestId,
+        Message as RequestResponseMessage, OutboundFailure, OutboundRequestId,
+        ProtocolSupport as RequestResponseProtocolSupport, ResponseChannel,
     },
     swarm::SwarmEvent,
     tls, yamux, Multiaddr, PeerId, StreamProtocol, Swarm,
 };
 use once_cell::sync::Lazy;
-use std::{error::Error, net::ToSocketAddrs, str::FromStr, sync::Arc, time::Duration};
-use subql_contracts::Network;
-use subql_indexer_utils::{
-    constants::{
-        METRICS_PEER_ID, PRODUCTION_BOOSTNODE_PEER_ID_LIST, TEST_BOOSTNODE_PEER_ID_LIST,
-        TEST_METRICS_PEER_ID,
-    },
-    p2p::Event,
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    net::ToSocketAddrs,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
 };
+use subql_indexer_utils::p2p::{Event, P2P_PROTOCOL_VERSION};
 use tokio::{
-    sync::{mpsc, Mutex},
+    sync::{mpsc, oneshot, Mutex},
     time,
 };
 
-static LAZY_STOP_SENDER: Lazy<Arc<Mutex<Option<mpsc::Sender<()>>>>> =
+/// notify the event loop to deliver the telemetry outbox
+static LAZY_OUTBOX_SENDER: Lazy<Arc<Mutex<Option<mpsc::Sender<()>>>>> =
     Lazy::new(|| Arc::new(Mutex::new(None)));
 
-static LAZY_EVENT_SENDER: Lazy<Arc<Mutex<Option<mpsc::Sender<Event>>>>> =
+static LAZY_DHT_SENDER: Lazy<Arc<Mutex<Option<mpsc::Sender<DhtCommand>>>>> =
     Lazy::new(|| Arc::new(Mutex::new(None)));
 
+static LAZY_GOSSIP_SENDER: Lazy<Arc<Mutex<Option<mpsc::Sender<(IdentTopic, Vec<u8>)>>>>> =
+    Lazy::new(|| Arc::new(Mutex::new(None)));
+
+/// the p2p reachability detected by AutoNAT, and the reserved relay peer
+static REACHABILITY: Lazy<Arc<Mutex<(NatStatus, Option<PeerId>)>>> =
+    Lazy::new(|| Arc::new(Mutex::new((NatStatus::Unknown, None))));
+
+/// the protocol of Kademlia DHT
+const KAD_PROTOCOL: &str = "/agent/connection/1.0.0";
+
+/// The commands of Kademlia DHT
+pub(crate) enum DhtCommand {
+    /// start providing the deployment, with the signed price record
+    Provide(String, Vec<u8>),
+    /// stop providing the deployment
+    StopProvide(String),
+    /// find the providers of deployment
+    FindProviders(String, oneshot::Sender<Vec<PeerId>>),
+    /// put the record value
+    PutRecord(RecordKey, Vec<u8>),
+    /// get the record value
+    GetRecord(RecordKey, oneshot::Sender<Option<Vec<u8>>>),
+}
+
 pub(crate) struct EventLoop {
     swarm: Swarm<AgentBehavior>,
     boot_node_peer_id: Option<PeerId>,
+    boot_node_multiaddr: Option<Multiaddr>,
+    nat_status: NatStatus,
+    /// the bootnode which reserved relay slot, and the circuit listener
+    relay_peer_id: Option<PeerId>,
+    relay_listener: Option<ListenerId>,
+    /// the event type of pending requests, for metrics
+    pending_outbound: HashMap<OutboundRequestId, &'static str>,
+    pending_inbound: HashMap<InboundRequestId, &'static str>,
     metrics_peer_id: Option<PeerId>,
     metrics_multiaddr: Option<Multiaddr>,
-    stop_receiver: mpsc::Receiver<()>,
-    event_receiver: mpsc::Receiver<Event>,
+    /// the peers dialed by us and the last used time, closed when idle
+    outbound_peers: HashMap<PeerId, Instant>,
+    outbox_receiver: mpsc::Receiver<()>,
+    /// the delivering telemetry event, waiting the ack of metrics node
+    outbox_inflight: Option<(OutboundRequestId, Vec<u8>, Instant)>,
+    response_sender: mpsc::Sender<(ResponseChannel<Event>, Event)>,
+    response_receiver: mpsc::Receiver<(ResponseChannel<Event>, Event)>,
+    dht_receiver: mpsc::Receiver<DhtCommand>,
+    gossip_receiver: mpsc::Receiver<(IdentTopic, Vec<u8>)>,
+    /// the gossip messages validated in task: message id, propagation source, acceptance
+    validation_sender: mpsc::Sender<(MessageId, PeerId, MessageAcceptance)>,
+    validation_receiver: mpsc::Receiver<(MessageId, PeerId, MessageAcceptance)>,
+    pending_providers: HashMap<QueryId, (HashSet<PeerId>, oneshot::Sender<Vec<PeerId>>)>,
+    pending_records: HashMap<QueryId, oneshot::Sender<Option<Vec<u8>>>>,
 }
 
 impl EventLoop {
     pub async fn new(local_key: Keypair) -> Result<Self, Box<dyn Error + Send + Sync>> {
         let swarm = Self::start_swarm(local_key.clone()).await?;
 
-        let (stop_sender, stop_receiver) = mpsc::channel::<()>(1);
+        let (outbox_sender, outbox_receiver) = mpsc::channel::<()>(10);
+        {
+            let mut outbox_lock = LAZY_OUTBOX_SENDER.lock().await;
+            *outbox_lock = Some(outbox_sender);
+        }
+        let (response_sender, response_receiver) = mpsc::channel(1024);
+        let (dht_sender, dht_receiver) = mpsc::channel::<DhtCommand>(1024);
         {
-            let mut stop_lock = LAZY_STOP_SENDER.lock().await;
-            *stop_lock = Some(stop_sender);
+            let mut dht_lock = LAZY_DHT_SENDER.lock().await;
+            *dht_lock = Some(dht_sender);
         }
-        let (event_sender, event_receiver) = mpsc::channel::<Event>(10);
+        let (gossip_sender, gossip_receiver) = mpsc::channel(1024);
+        let (validation_sender, validation_receiver) = mpsc::channel(1024);
         {
-            let mut event_lock = LAZY_EVENT_SENDER.lock().await;
-            *event_lock = Some(event_sender);
+            let mut gossip_lock = LAZY_GOSSIP_SENDER.lock().await;
+            *gossip_lock = Some(gossip_sender);
         }
         Ok(Self {
             swarm,
             boot_node_peer_id: None,
+            boot_node_multiaddr: None,
+            nat_status: NatStatus::Unknown,
+            relay_peer_id: None,
+            relay_listener: None,
+            pending_outbound: HashMap::new(),
+            pending_inbound: HashMap::new(),
             metrics_peer_id: None,
             metrics_multiaddr: None,
-            stop_receiver,
-            event_receiver,
+            outbound_peers: HashMap::new(),
+            outbox_receiver,
+            outbox_inflight: None,
+            response_sender,
+            response_receiver,
+            dht_receiver,
+            gossip_receiver,
+            validation_sender,
+            validation_receiver,
+            pending_providers: HashMap::new(),
+            pending_records: HashMap::new(),
         })
     }
 
     pub async fn start_swarm(
         local_key: Keypair,
     ) -> Result<Swarm<AgentBehavior>, Box<dyn Error + Send + Sync>> {
-        let mut swarm = libp2p::SwarmBuilder::with_existing_identity(local_key.clone())
+        let mut swarm = Self::build_swarm(local_key)?;
+
+        for listen_addr in COMMAND.p2p() {
+            let listen_addr = listen_addr.clone();
+            let listen_addr = listen_addr.parse::<Multiaddr>()?;
+            swarm.listen_on(listen_addr)?;
+        }
+
+        swarm.behaviour_mut().gossipsub.subscribe(&price_topic())?;
+        swarm
+            .behaviour_mut()
+            .gossipsub
+            .subscribe(&healthy_topic())?;
+
+        Ok(swarm)
+    }
+
+    fn build_swarm(
+        local_key: Keypair,
+    ) -> Result<Swarm<AgentBehavior>, Box<dyn Error + Send + Sync>> {
+        let swarm = libp2p::SwarmBuilder::with_existing_identity(local_key.clone())
             .with_tokio()
             .with_tcp(
                 Default::default(),
@@ -90,18 +204,24 @@ impl EventLoop {
             )?
             .with_quic()
             .with_dns()?
-            .with_behaviour(|key| {
+            .with_relay_client(noise::Config::new, yamux::Config::default)?
+            .with_behaviour(|key, relay_client| {
                 let local_peer_id = PeerId::from(key.clone().public());
 
-                let kad_config = KadConfig::new(StreamProtocol::new("/agent/connection/1.0.0"));
+                let kad_config = KadConfig::new(StreamProtocol::new(KAD_PROTOCOL));
                 let kad_memory = KadInMemory::new(local_peer_id);
                 let kad = KadBehavior::with_config(local_peer_id, kad_memory, kad_config);
 
-                let rr_config =
-                    RequestResponseConfig::default().with_max_concurrent_streams(1024 * 1024);
-                let rr_protocol = StreamProtocol::new("/agent/message/1.0.0");
-                let rr_behavior = RequestResponseBehavior::<Event, Event>::new(
-                    [(rr_protocol, RequestResponseProtocolSupport::Outbound)],
+                let rr_config = RequestResponseConfig::default()
+                    .with_max_concurrent_streams(1024 * 1024)
+                    .with_request_timeout(Duration::from_secs(P2P_REQUEST_TIMEOUT));
+                // prefer the envelope protocol, fallback to legacy json
+                let rr_behavior = RequestResponseBehavior::with_codec(
+                    EventCodec,
+                    [
+                        (PROTOCOL_V2, RequestResponseProtocolSupport::Full),
+                        (PROTOCOL_V1, RequestResponseProtocolSupport::Full),
+                    ],
                     rr_config,
                 );
 
@@ -109,6 +229,11 @@ impl EventLoop {
                     "/agent/connection/1.0.0".to_string(),
                     key.clone().public(),
                 )
+                .with_agent_version(format!(
+                    "subql-indexer-proxy/{}/p2p-{}",
+                    env!("CARGO_PKG_VERSION"),
+                    P2P_PROTOCOL_VERSION
+                ))
                 .with_push_listen_addr_updates(true)
                 .with_interval(Duration::from_secs(30));
                 let identify = IdentifyBehavior::new(identify_config);
@@ -117,17 +242,34 @@ impl EventLoop {
                     ping::Config::new().with_interval(Duration::from_secs(10)),
                 );
 
-                AgentBehavior::new(kad, identify, rr_behavior, ping)
+                // the messages are signed by peer key, and validated by the announcements
+                let gossipsub_config = GossipsubConfigBuilder::default()
+                    .validation_mode(ValidationMode::Strict)
+                    .validate_messages()
+                    .build()?;
+                let gossipsub = GossipsubBehavior::new(
+                    MessageAuthenticity::Signed(key.clone()),
+                    gossipsub_config,
+                )?;
+
+                // detect reachability, reserve relay and hole punching when behind NAT
+                let autonat = AutonatBehavior::new(local_peer_id, AutonatConfig::default());
+                let dcutr = DcutrBehavior::new(local_peer_id);
+
+                Ok::<_, Box<dyn Error + Send + Sync>>(AgentBehavior::new(
+                    kad,
+                    identify,
+                    rr_behavior,
+                    ping,
+                    gossipsub,
+                    autonat,
+                    relay_client,
+                    dcutr,
+                ))
             })?
             .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(u64::MAX)))
             .build();
 
-        for listen_addr in COMMAND.p2p() {
-            let listen_addr = listen_addr.clone();
-            let listen_addr = listen_addr.parse::<Multiaddr>()?;
-            swarm.listen_on(listen_addr)?;
-        }
-
         Ok(swarm)
     }
 
@@ -168,6 +310,9 @@ impl EventLoop {
                             resolved_multiaddrs.push(resolved_multiaddr);
                         }
                     }
+                } else if dns_name.is_none() {
+                    // the ip multiaddr, e.g. local bootnode
+                    resolved_multiaddrs.push(multiaddr);
                 }
             }
         }
@@ -191,6 +336,8 @@ impl EventLoop {
 
         let mut interval_connect_bootnode = time::interval(Duration::from_secs(120));
 
+        let mut interval_provide = time::interval(Duration::from_secs(P2P_PROVIDE_TIME));
+
         tokio::time::sleep(Duration::from_secs(3)).await;
         self.connect_to_boot_metrics_node().await;
 
@@ -199,29 +346,52 @@ impl EventLoop {
             tokio::select! {
                 event = self.swarm.select_next_some() => self.handle_event(event).await,
                 _ = interval_project_report_metrics.tick() => {
-                    if COMMAND.telemetry {
+                    if COMMAND.telemetry() {
                         let indexer = get_indexer().await;
                         let indexer_network = format!("{}:{}", indexer, COMMAND.network);
                         let metrics = get_timer_metrics().await;
                         let message = Event::MetricsQueryCount2(indexer_network, metrics);
-                        if let Some(metrics_peer_id) = self.metrics_peer_id {
-                            _ = self.swarm.behaviour_mut().rr.send_request(&metrics_peer_id, message);
-                        }
+                        outbox::push(&message).await;
+                        self.deliver_outbox().await;
                     }
                 }
                 _ = interval_project_broadcast_healthy.tick() => {
-                    if COMMAND.telemetry {
+                    if COMMAND.telemetry() {
                         let healthy = indexer_healthy().await;
                         let data = serde_json::to_string(&healthy).unwrap_or("".to_owned());
                         let message = Event::IndexerHealthy(data);
                         if let Some(metrics_peer_id) = self.metrics_peer_id {
-                            _ = self.swarm.behaviour_mut().rr.send_request(&metrics_peer_id, message);
+                            self.send_request(&metrics_peer_id, message);
+                        }
+
+                        let announcement = healthy_announcement().await;
+                        if !announcement.is_empty() {
+                            _ = self.swarm.behaviour_mut().gossipsub.publish(healthy_topic(), announcement);
                         }
                     }
                 }
                 _ = interval_connect_bootnode.tick() => {
                     self.connect_boot_node().await;
                 }
+                _ = interval_provide.tick() => {
+                    tokio::spawn(provide_all_deployments());
+                    tokio::spawn(publish_attestation());
+                    tokio::spawn(announce_all_prices());
+                }
+                Some(command) = self.dht_receiver.recv() => {
+                    self.handle_dht_command(command);
+                }
+                Some((topic, data)) = self.gossip_receiver.recv() => {
+                    if let Err(err) = self.swarm.behaviour_mut().gossipsub.publish(topic, data) {
+                        debug!("Gossip publish failure: {:?}", err);
+                    }
+                }
+                Some((message_id, source, acceptance)) = self.validation_receiver.recv() => {
+                    self.swarm
+                        .behaviour_mut()
+                        .gossipsub
+                        .report_message_validation_result(&message_id, &source, acceptance);
+                }
                 // _ = interval_report_status.tick() => {
                 //     if let Some(metrics_peer_id) = self.metrics_peer_id {
                 //         let map = PROJECTS.lock().await;
@@ -236,15 +406,11 @@ impl EventLoop {
                 //         drop(map);
                 //     }
                 // }
-                Some(event) = self.event_receiver.recv() => {
-                    if let Some(metrics_peer_id) = self.metrics_peer_id {
-                        _ = self.swarm.behaviour_mut().rr.send_request(&metrics_peer_id, event);
-                    }
+                Some((channel, response)) = self.response_receiver.recv() => {
+                    _ = self.swarm.behaviour_mut().rr.send_response(channel, response);
                 }
-                _ = self.stop_receiver.recv() => {
-                    // Stop signal received, gracefully exit the loop
-                    warn!("Received stop signal, stopping event loop...");
-                    break;
+                Some(_) = self.outbox_receiver.recv() => {
+                    self.deliver_outbox().await;
                 }
                 _ = check_metrics_interval.tick() => {
                     if let Some(metrics_peer_id) = self.metrics_peer_id {
@@ -255,51 +421,50 @@ impl EventLoop {
                     } else {
                         self.rennect_to_metrics_node().await;
                     }
+                    // retry the undelivered telemetry
+                    self.deliver_outbox().await;
+                    // retry the relay reservation
+                    self.reserve_relay().await;
+                    self.close_idle_connections();
+                    self.update_connection_metrics().await;
                 }
             }
         }
     }
 
-    pub async fn stop() {
-        let lock = LAZY_STOP_SENDER.lock().await;
-        if let Some(sender) = &*lock {
-            // Use `sender` here safely
-            warn!("send stop signal...");
-            _ = sender.send(()).await;
-        }
-    }
-
     pub async fn handle_event(&mut self, event: SwarmEvent<AgentEvent>) {
+        let connection_changed = matches!(
+            event,
+            SwarmEvent::ConnectionEstablished { .. } | SwarmEvent::ConnectionClosed { .. }
+        );
+
         match event {
             SwarmEvent::ConnectionEstablished {
-                peer_id,
-                connection_id,
-                endpoint,
-                ..
-            } => match Self::is_peer_metrics_node(&peer_id).await {
-                true => {
+                peer_id, endpoint, ..
+            } => {
+                if endpoint.is_dialer() {
+                    self.outbound_peers
+                        .entry(peer_id)
+                        .or_insert_with(Instant::now);
+                }
+                if Self::is_peer_metrics_node(&peer_id).await {
                     self.metrics_peer_id = Some(peer_id);
                     if let ConnectedPoint::Dialer { address, .. } = endpoint {
                         self.metrics_multiaddr = Some(address.clone());
                     }
-                    if let Some(connected_boot_peer) = self.boot_node_peer_id {
-                        _ = self.swarm.disconnect_peer_id(connected_boot_peer);
-                        self.boot_node_peer_id = None;
+                    self.deliver_outbox().await;
+                } else if Self::is_peer_bootnode_node(&peer_id).await {
+                    // the bootnode is the entry of DHT, and the relay when behind NAT
+                    self.boot_node_peer_id = Some(peer_id);
+                    if let ConnectedPoint::Dialer { address, .. } = &endpoint {
+                        self.boot_node_multiaddr = Some(address.clone());
                     }
-                }
-                _ => match self.metrics_peer_id {
-                    None => {
-                        if Self::is_peer_bootnode_node(&peer_id).await {
-                            self.boot_node_peer_id = Some(peer_id);
-                        } else {
-                            self.swarm.close_connection(connection_id);
-                        }
-                    }
-                    Some(_metrics_peer_id) => {
-                        self.swarm.close_connection(connection_id);
+                    if let Err(err) = self.swarm.behaviour_mut().kad.bootstrap() {
+                        debug!("DHT bootstrap failure: {:?}", err);
                     }
-                },
-            },
+                    self.reserve_relay().await;
+                }
+            }
             SwarmEvent::OutgoingConnectionError {
                 connection_id,
                 peer_id,
@@ -319,6 +484,8 @@ impl EventLoop {
                 ..
             } => {
                 if num_established == 0 {
+                    self.outbound_peers.remove(&peer_id);
+
                     if Self::is_peer_metrics_node(&peer_id).await {
                         self.metrics_peer_id = None;
                         self.metrics_multiaddr = None;
@@ -326,10 +493,37 @@ impl EventLoop {
 
                     if Self::is_peer_bootnode_node(&peer_id).await {
                         self.boot_node_peer_id = None;
+                        self.boot_node_multiaddr = None;
+                    }
+
+                    if self.relay_peer_id == Some(peer_id) {
+                        self.relay_peer_id = None;
+                        self.update_reachability().await;
                     }
                 }
             }
 
+            SwarmEvent::NewListenAddr { address, .. } => {
+                update_metrics_p2p_address("listen", address.to_string(), true).await;
+            }
+            SwarmEvent::ExpiredListenAddr { address, .. } => {
+                update_metrics_p2p_address("listen", address.to_string(), false).await;
+            }
+            SwarmEvent::ExternalAddrConfirmed { address } => {
+                update_metrics_p2p_address("external", address.to_string(), true).await;
+            }
+            SwarmEvent::ExternalAddrExpired { address } => {
+                update_metrics_p2p_address("external", address.to_string(), false).await;
+            }
+
+            SwarmEvent::ListenerClosed { listener_id, .. } => {
+                if self.relay_listener == Some(listener_id) {
+                    self.relay_listener = None;
+                    self.relay_peer_id = None;
+                    self.update_reachability().await;
+                }
+            }
+
             SwarmEvent::Behaviour(AgentEvent::Identify(sub_event)) => {
                 self.handle_identify_event(sub_event).await
             }
@@ -342,29 +536,375 @@ impl EventLoop {
             SwarmEvent::Behaviour(AgentEvent::Ping(sub_event)) => {
                 self.handle_ping_event(sub_event).await
             }
+            SwarmEvent::Behaviour(AgentEvent::Gossipsub(sub_event)) => {
+                self.handle_gossipsub_event(sub_event)
+            }
+            SwarmEvent::Behaviour(AgentEvent::Autonat(sub_event)) => {
+                self.handle_autonat_event(sub_event).await
+            }
+            SwarmEvent::Behaviour(AgentEvent::RelayClient(sub_event)) => {
+                self.handle_relay_client_event(sub_event).await
+            }
+            SwarmEvent::Behaviour(AgentEvent::Dcutr(sub_event)) => {
+                self.handle_dcutr_event(sub_event)
+            }
             _ => {
                 //warn!("not handled event is {:?}", event);
             }
         }
+
+        if connection_changed {
+            self.update_connection_metrics().await;
+        }
     }
 
     async fn handle_identify_event(&mut self, event: IdentifyEvent) {
-        if self.metrics_multiaddr.is_none() {
-            if let IdentifyEvent::Received { peer_id, info, .. } = event {
-                if self.boot_node_peer_id.is_some() {
-                    for addr in info.clone().listen_addrs {
-                        self.swarm.behaviour_mut().kad.add_address(&peer_id, addr);
+        if let IdentifyEvent::Received { peer_id, info, .. } = event {
+            // the peers run the DHT protocol can be routed
+            let kad_protocol = StreamProtocol::new(KAD_PROTOCOL);
+            if info.protocols.contains(&kad_protocol) {
+                for addr in info.listen_addrs {
+                    self.swarm.behaviour_mut().kad.add_address(&peer_id, addr);
+                }
+            }
+        }
+    }
+
+    async fn handle_kad_event(&mut self, event: KademliaEvent) {
+        if let KademliaEvent::OutboundQueryProgressed {
+            id, result, step, ..
+        } = event
+        {
+            match result {
+                QueryResult::GetProviders(Ok(GetProvidersOk::FoundProviders {
+                    providers, ..
+                })) => {
+                    if let Some((found, _)) = self.pending_providers.get_mut(&id) {
+                        found.extend(providers);
+                    }
+                }
+                QueryResult::GetRecord(Ok(GetRecordOk::FoundRecord(peer_record))) => {
+                    if let Some(sender) = self.pending_records.remove(&id) {
+                        _ = sender.send(Some(peer_record.record.value));
+                    }
+                    // one record is enough
+                    if let Some(mut query) = self.swarm.behaviour_mut().kad.query_mut(&id) {
+                        query.finish();
                     }
                 }
+                _ => {}
             }
+
+            if step.last {
+                if let Some((found, sender)) = self.pending_providers.remove(&id) {
+                    _ = sender.send(found.into_iter().collect());
+                }
+                if let Some(sender) = self.pending_records.remove(&id) {
+                    _ = sender.send(None);
+                }
+            }
+        }
+    }
+
+    async fn handle_autonat_event(&mut self, event: AutonatEvent) {
+        if let AutonatEvent::StatusChanged { old, new } = event {
+            info!("P2P reachability changed: {:?} => {:?}", old, new);
+            self.nat_status = new;
+            if matches!(self.nat_status, NatStatus::Public(_)) {
+                // reachable directly, no need the relay
+                if let Some(listener) = self.relay_listener.take() {
+                    self.swarm.remove_listener(listener);
+                }
+                self.relay_peer_id = None;
+            } else {
+                self.reserve_relay().await;
+            }
+            self.update_reachability().await;
         }
     }
 
-    async fn handle_kad_event(&mut self, _event: KademliaEvent) {}
+    async fn handle_relay_client_event(&mut self, event: RelayClientEvent) {
+        if let RelayClientEvent::ReservationReqAccepted { relay_peer_id, .. } = event {
+            info!("P2P relay reserved on {}", relay_peer_id);
+            self.relay_peer_id = Some(relay_peer_id);
+            self.update_reachability().await;
+        }
+    }
 
-    async fn handle_request_response_event(&mut self, _event: RequestResponseEvent<Event, Event>) {}
+    fn handle_dcutr_event(&mut self, event: DcutrEvent) {
+        match event.result {
+            Ok(_) => debug!("P2P hole punched with {}", event.remote_peer_id),
+            Err(err) => debug!("P2P hole punch {} failure: {:?}", event.remote_peer_id, err),
+        }
+    }
 
-    async fn handle_ping_event(&mut self, _event: PingEvent) {}
+    /// reserve a relay slot on the connected bootnode when behind NAT
+    async fn reserve_relay(&mut self) {
+        if self.relay_listener.is_some() || !matches!(self.nat_status, NatStatus::Private) {
+            return;
+        }
+
+        match (self.boot_node_peer_id, &self.boot_node_multiaddr) {
+            (Some(peer_id), Some(address)) => {
+                let circuit = address
+                    .clone()
+                    .with(Protocol::P2p(peer_id))
+                    .with(Protocol::P2pCircuit);
+                match self.swarm.listen_on(circuit) {
+                    Ok(listener) => {
+                        self.relay_listener = Some(listener);
+                        self.relay_peer_id = Some(peer_id);
+                    }
+                    Err(err) => warn!("P2P relay listen failure: {:?}", err),
+                }
+            }
+            _ => self.connect_to_boot_metrics_node().await,
+        }
+    }
+
+    async fn update_reachability(&self) {
+        let mut lock = REACHABILITY.lock().await;
+        *lock = (self.nat_status.clone(), self.relay_peer_id);
+    }
+
+    fn handle_gossipsub_event(&mut self, event: GossipsubEvent) {
+        if let GossipsubEvent::Message {
+            propagation_source,
+            message_id,
+            message,
+        } = event
+        {
+            // validate in task, need lookup the attestation of author in DHT
+            let sender = self.validation_sender.clone();
+            tokio::spawn(async move {
+                let acceptance = if validate(message.source, &message.topic, &message.data).await {
+                    MessageAcceptance::Accept
+                } else {
+                    MessageAcceptance::Reject
+                };
+                _ = sender
+                    .send((message_id, propagation_source, acceptance))
+                    .await;
+            });
+        }
+    }
+
+    fn handle_dht_command(&mut self, command: DhtCommand) {
+        let local_peer_id = *self.swarm.local_peer_id();
+        let kad = &mut self.swarm.behaviour_mut().kad;
+        match command {
+            DhtCommand::Provide(deployment, value) => {
+                if let Err(err) = kad.start_providing(provider_key(&deployment)) {
+                    warn!("DHT start providing {} failure: {:?}", deployment, err);
+                }
+                let record = Record::new(price_key(&deployment, &local_peer_id), value);
+                if let Err(err) = kad.put_record(record, Quorum::One) {
+                    warn!("DHT put record {} failure: {:?}", deployment, err);
+                }
+            }
+            DhtCommand::StopProvide(deployment) => {
+                kad.stop_providing(&provider_key(&deployment));
+                kad.remove_record(&price_key(&deployment, &local_peer_id));
+            }
+            DhtCommand::FindProviders(deployment, sender) => {
+                let id = kad.get_providers(provider_key(&deployment));
+                self.pending_providers.insert(id, (HashSet::new(), sender));
+            }
+            DhtCommand::PutRecord(key, value) => {
+                if let Err(err) = kad.put_record(Record::new(key, value), Quorum::One) {
+                    warn!("DHT put record failure: {:?}", err);
+                }
+            }
+            DhtCommand::GetRecord(key, sender) => {
+                let id = kad.get_record(key);
+                self.pending_records.insert(id, sender);
+            }
+        }
+    }
+
+    async fn handle_request_response_event(&mut self, event: RequestResponseEvent<Event, Event>) {
+        match event {
+            RequestResponseEvent::Message {
+                peer,
+                message:
+                    RequestResponseMessage::Request {
+                        request_id,
+                        request,
+                        channel,
+                    },
+                ..
+            } => {
+                if let Some(used_at) = self.outbound_peers.get_mut(&peer) {
+                    *used_at = Instant::now();
+                }
+                self.pending_inbound.insert(request_id, request.name());
+                // handle in task, the response will send back by channel
+                let sender = self.response_sender.clone();
+                tokio::spawn(async move {
+                    if let Some(response) = handle_request(request).await {
+                        _ = sender.send((channel, response)).await;
+                    }
+                });
+            }
+            RequestResponseEvent::Message {
+                message: RequestResponseMessage::Response { request_id, .. },
+                ..
+            } => {
+                if let Some(name) = self.pending_outbound.remove(&request_id) {
+                    add_metrics_p2p_request(name, "outbound", "success").await;
+                }
+                // the metrics node acked, deliver next
+                if let Some((id, bytes, sent)) = self.outbox_inflight.take() {
+                    if id == request_id {
+                        outbox::ack(&bytes).await;
+                        self.deliver_outbox().await;
+                    } else {
+                        self.outbox_inflight = Some((id, bytes, sent));
+                    }
+                }
+            }
+            RequestResponseEvent::OutboundFailure {
+                request_id, error, ..
+            } => {
+                if let Some(name) = self.pending_outbound.remove(&request_id) {
+                    let result = match error {
+                        OutboundFailure::Timeout => "timeout",
+                        _ => "failure",
+                    };
+                    add_metrics_p2p_request(name, "outbound", result).await;
+                }
+                // keep in outbox, retry later
+                if matches!(&self.outbox_inflight, Some((id, _, _)) if *id == request_id) {
+                    self.outbox_inflight = None;
+                }
+            }
+            RequestResponseEvent::InboundFailure {
+                request_id, error, ..
+            } => {
+                if let Some(name) = self.pending_inbound.remove(&request_id) {
+                    let result = match error {
+                        InboundFailure::Timeout => "timeout",
+                        _ => "failure",
+                    };
+                    add_metrics_p2p_request(name, "inbound", result).await;
+                }
+            }
+            RequestResponseEvent::ResponseSent { request_id, .. } => {
+                if let Some(name) = self.pending_inbound.remove(&request_id) {
+                    add_metrics_p2p_request(name, "inbound", "success").await;
+                }
+            }
+        }
+    }
+
+    /// send request to peer, and record the event type for metrics
+    fn send_request(&mut self, peer_id: &PeerId, event: Event) -> OutboundRequestId {
+        let name = event.name();
+        if let Some(used_at) = self.outbound_peers.get_mut(peer_id) {
+            *used_at = Instant::now();
+        }
+        let id = self.swarm.behaviour_mut().rr.send_request(peer_id, event);
+        self.pending_outbound.insert(id, name);
+        id
+    }
+
+    async fn update_connection_metrics(&self) {
+        let peers = self.swarm.connected_peers().count();
+        let bootnode = self
+            .boot_node_peer_id
+            .map(|p| self.swarm.is_connected(&p))
+            .unwrap_or(false);
+        let metrics = self
+            .metrics_peer_id
+            .map(|p| self.swarm.is_connected(&p))
+            .unwrap_or(false);
+        update_metrics_p2p_connections(peers, bootnode, metrics).await;
+    }
+
+    /// close the outbound connections after idle time, keep the metrics node, bootnode,
+    /// relay, the peers in DHT routing table or gossip mesh, and the peers with requests
+    fn close_idle_connections(&mut self) {
+        let mut keep: HashSet<PeerId> = self
+            .swarm
+            .behaviour_mut()
+            .kad
+            .kbuckets()
+            .flat_map(|bucket| {
+                bucket
+                    .iter()
+                    .map(|entry| *entry.node.key.preimage())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        keep.extend(
+            self.swarm
+                .behaviour()
+                .gossipsub
+                .all_peers()
+                .filter(|(_, topics)| !topics.is_empty())
+                .map(|(peer, _)| *peer),
+        );
+        keep.extend(self.metrics_peer_id);
+        keep.extend(self.boot_node_peer_id);
+        keep.extend(self.relay_peer_id);
+
+        let idle: Vec<PeerId> = self
+            .outbound_peers
+            .iter()
+            .filter(|(peer, at)| {
+                at.elapsed().as_secs() > P2P_IDLE_CONNECTION_TIME && !keep.contains(peer)
+            })
+            .map(|(peer, _)| *peer)
+            .collect();
+        for peer in idle {
+            self.outbound_peers.remove(&peer);
+            _ = self.swarm.disconnect_peer_id(peer);
+        }
+    }
+
+    /// send the oldest telemetry event to metrics node, one by one
+    async fn deliver_outbox(&mut self) {
+        if let Some((_, _, sent)) = &self.outbox_inflight {
+            if sent.elapsed() < Duration::from_secs(P2P_OUTBOX_TIMEOUT) {
+                return;
+            }
+            // metrics node not ack in time, resend it, the late response will be ignored
+            self.outbox_inflight = None;
+        }
+        let metrics_peer_id = if let Some(peer_id) = self.metrics_peer_id {
+            peer_id
+        } else {
+            return;
+        };
+
+        while let Some(bytes) = outbox::front().await {
+            match Event::from_bytes(&bytes) {
+                Ok(Event::Unknown(..)) | Err(_) => {
+                    // broken event, cannot deliver
+                    outbox::ack(&bytes).await;
+                    add_metrics_telemetry_dropped(1);
+                }
+                Ok(event) => {
+                    let id = self.send_request(&metrics_peer_id, event);
+                    self.outbox_inflight = Some((id, bytes, Instant::now()));
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn handle_ping_event(&mut self, event: PingEvent) {
+        if let Ok(rtt) = event.result {
+            let node = if Self::is_peer_metrics_node(&event.peer).await {
+                "metrics"
+            } else if Self::is_peer_bootnode_node(&event.peer).await {
+                "bootnode"
+            } else {
+                "peer"
+            };
+            update_metrics_p2p_ping(node, rtt.as_millis() as u64).await;
+        }
+    }
 
     async fn connect_boot_node(&mut self) {
         if self.metrics_peer_id.is_none() {
@@ -385,13 +925,35 @@ impl EventLoop {
         }
     }
 
+    /// buffer the telemetry event in outbox, and notify the event loop to deliver
     pub async fn send_p2p_event(event: Event) {
+        if !COMMAND.telemetry() {
+            return;
+        }
         info!("send event: {:?}", event);
-        let lock = LAZY_EVENT_SENDER.lock().await;
-        if let Some(event_sender) = &*lock {
-            // Use `sender` here safely
-            warn!("send stop signal...");
-            _ = event_sender.send(event).await;
+        outbox::push(&event).await;
+        let lock = LAZY_OUTBOX_SENDER.lock().await;
+        if let Some(outbox_sender) = &*lock {
+            // already has pending notification when full
+            _ = outbox_sender.try_send(());
+        }
+    }
+
+    /// publish the message to gossip topic
+    pub async fn publish(topic: IdentTopic, data: Vec<u8>) {
+        let lock = LAZY_GOSSIP_SENDER.lock().await;
+        if let Some(sender) = &*lock {
+            _ = sender.send((topic, data)).await;
+        }
+    }
+
+    /// send the command to DHT, false when the p2p network is not running
+    pub async fn send_dht_command(command: DhtCommand) -> bool {
+        let lock = LAZY_DHT_SENDER.lock().await;
+        if let Some(sender) = &*lock {
+            sender.send(command).await.is_ok()
+        } else {
+            false
         }
     }
 
@@ -403,16 +965,111 @@ impl EventLoop {
         }
     }
 
+    /// the p2p reachability, whether the p2p serving works
+    pub async fn reachability() -> Value {
+        let lock = REACHABILITY.lock().await;
+        let (status, relay) = lock.clone();
+        drop(lock);
+
+        let status = match status {
+            _ if COMMAND.no_p2p => "disabled",
+            NatStatus::Public(_) => "public",
+            NatStatus::Private => "private",
+            NatStatus::Unknown => "unknown",
+        };
+        json!({
+            "reachability": status,
+            "relay": relay.map(|p| p.to_base58()),
+        })
+    }
+
     pub async fn is_peer_metrics_node(peer_id: &PeerId) -> bool {
-        (COMMAND.network() == Network::Mainnet && peer_id.to_base58() == METRICS_PEER_ID)
-            || (COMMAND.network() == Network::Testnet
-                && peer_id.to_base58() == TEST_METRICS_PEER_ID)
+        COMMAND.metrics_peer_ids().contains(&peer_id.to_base58())
     }
 
     pub async fn is_peer_bootnode_node(peer_id: &PeerId) -> bool {
-        (COMMAND.network() == Network::Mainnet
-            && PRODUCTION_BOOSTNODE_PEER_ID_LIST.contains(&peer_id.to_base58().as_str()))
-            || (COMMAND.network() == Network::Testnet
-                && TEST_BOOSTNODE_PEER_ID_LIST.contains(&peer_id.to_base58().as_str()))
+        COMMAND.bootnode_peer_ids().contains(&peer_id.to_base58())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::kad::Mode;
+
+    /// the event loop with local channels, not registered to the global senders
+    async fn test_event_loop() -> (EventLoop, Multiaddr) {
+        let mut swarm = EventLoop::build_swarm(Keypair::generate_secp256k1()).unwrap();
+        swarm.behaviour_mut().kad.set_mode(Some(Mode::Server));
+        swarm
+            .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .unwrap();
+        let addr = loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = swarm.select_next_some().await {
+                break address;
+            }
+        };
+
+        let (_, outbox_receiver) = mpsc::channel(1);
+        let (response_sender, response_receiver) = mpsc::channel(1024);
+        let (_, dht_receiver) = mpsc::channel(1);
+        let (_, gossip_receiver) = mpsc::channel(1);
+        let (validation_sender, validation_receiver) = mpsc::channel(1);
+        let event_loop = EventLoop {
+            swarm,
+            boot_node_peer_id: None,
+            boot_node_multiaddr: None,
+            nat_status: NatStatus::Unknown,
+            relay_peer_id: None,
+            relay_listener: None,
+            pending_outbound: HashMap::new(),
+            pending_inbound: HashMap::new(),
+            metrics_peer_id: None,
+            metrics_multiaddr: None,
+            outbound_peers: HashMap::new(),
+            outbox_receiver,
+            outbox_inflight: None,
+            response_sender,
+            response_receiver,
+            dht_receiver,
+            gossip_receiver,
+            validation_sender,
+            validation_receiver,
+            pending_providers: HashMap::new(),
+            pending_records: HashMap::new(),
+        };
+        (event_loop, addr)
+    }
+
+    #[tokio::test]
+    async fn provide_and_find_providers() {
+        let (mut provider, provider_addr) = test_event_loop().await;
+        let (mut finder, _) = test_event_loop().await;
+        let provider_peer = *provider.swarm.local_peer_id();
+
+        provider.handle_dht_command(DhtCommand::Provide("QmTest".to_owned(), vec![1]));
+        finder
+            .swarm
+            .behaviour_mut()
+            .kad
+            .add_address(&provider_peer, provider_addr);
+        let (sender, mut receiver) = oneshot::channel();
+        finder.handle_dht_command(DhtCommand::FindProviders("QmTest".to_owned(), sender));
+
+        let found = time::timeout(Duration::from_secs(30), async {
+            loop {
+                tokio::select! {
+                    event = provider.swarm.select_next_some() => provider.handle_event(event).await,
+                    event = finder.swarm.select_next_some() => finder.handle_event(event).await,
+                    res = &mut receiver => break res.unwrap(),
+                }
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(found, vec![provider_peer]);
+        // the connection dialed by DHT query is kept
+        assert!(finder.swarm.is_connected(&provider_peer));
     }
 }
@@ -0,0 +1,60 @@
+use redis::RedisResult;
+use subql_indexer_utils::p2p::Event;
+
+use crate::cli::{redis, COMMAND};
+use crate::metrics::add_metrics_telemetry_dropped;
+
+/// the redis list of telemetry events waiting for the metrics node
+const OUTBOX_KEY: &str = "telemetry-outbox";
+
+/// push the event to outbox, drop the oldest events when it is full
+pub async fn push(event: &Event) {
+    let max = COMMAND.telemetry_outbox_size.max(1);
+
+    let mut conn = redis();
+    let len: RedisResult<u64> = redis::cmd("RPUSH")
+        .arg(OUTBOX_KEY)
+        .arg(event.to_bytes())
+        .query_async(&mut conn)
+        .await;
+    match len {
+        Ok(len) if len > max => {
+            let _: RedisResult<()> = redis::cmd("LTRIM")
+                .arg(OUTBOX_KEY)
+                .arg(-(max as i64))
+                .arg(-1)
+                .query_async(&mut conn)
+                .await;
+            add_metrics_telemetry_dropped(len - max);
+        }
+        Ok(_) => {}
+        Err(err) => {
+            error!("Redis outbox: {}", err);
+            add_metrics_telemetry_dropped(1);
+        }
+    }
+}
+
+/// the oldest event in outbox
+pub async fn front() -> Option<Vec<u8>> {
+    let mut conn = redis();
+    let bytes: RedisResult<Option<Vec<u8>>> = redis::cmd("LINDEX")
+        .arg(OUTBOX_KEY)
+        .arg(0)
+        .query_async(&mut conn)
+        .await;
+    bytes.ok().flatten()
+}
+
+/// remove the event which acked by metrics node, it is the front if not trimmed
+pub async fn ack(bytes: &[u8]) {
+    if front().await.as_deref() != Some(bytes) {
+        return;
+    }
+
+    let mut conn = redis();
+    let _: RedisResult<()> = redis::cmd("LPOP")
+        .arg(OUTBOX_KEY)
+        .query_async(&mut conn)
+        .await;
+}
@@ -37,8 +37,8 @@ use subql_indexer_utils::{
     p2p::Event,
     payg::{
         convert_sign_to_string, convert_string_to_sign, extend_recover2, extend_sign2,
-        price_recover, price_sign, MultipleQueryState, MultipleQueryStateActive, OpenState,
-        QueryState, MULTIPLE_RANGE_MAX,
+        price_recover, price_sign, price_tag_sign, MultipleQueryState, MultipleQueryStateActive,
+        OpenState, QueryState, MULTIPLE_RANGE_MAX,
     },
     request::{graphql_request, GraphQLQuery},
     tools::{cid_deployment, deployment_cid},
@@ -233,13 +233,50 @@ async fn build_project_price(
     // price + price_token + price_expired
     let sign = price_sign(price, token, expired, controller).await?;
 
+    if !project.is_ai_project() {
+        return Ok(json!((
+            project.id,
+            price.to_string(),
+            project.payg_expiration.to_string(),
+            format!("{:?}", token),
+            expired,
+            convert_sign_to_string(&sign)
+        )));
+    }
+
+    // AI project with output token price and model prices, signed with the tag,
+    // cannot be used as the unit price. append as an object, keep the 6 elements same
+    let output_price = project.ai_pricing("").output;
+    let output_sign = price_tag_sign("output", output_price, token, expired, controller).await?;
+    let mut models = json!({});
+    for model in project.payg_model_prices.keys() {
+        let pricing = project.ai_pricing(model);
+        let input_tag = format!("model:{}:input", model);
+        let output_tag = format!("model:{}:output", model);
+        let input_sign =
+            price_tag_sign(&input_tag, pricing.input, token, expired, controller).await?;
+        let output_sign =
+            price_tag_sign(&output_tag, pricing.output, token, expired, controller).await?;
+        models[model] = json!({
+            "inputPrice": pricing.input.to_string(),
+            "inputSignature": convert_sign_to_string(&input_sign),
+            "outputPrice": pricing.output.to_string(),
+            "outputSignature": convert_sign_to_string(&output_sign),
+        });
+    }
+
     Ok(json!((
         project.id,
         price.to_string(),
         project.payg_expiration.to_string(),
         format!("{:?}", token),
         expired,
-        convert_sign_to_string(&sign)
+        convert_sign_to_string(&sign),
+        json!({
+            "outputPrice": output_price.to_string(),
+            "outputSignature": convert_sign_to_string(&output_sign),
+            "models": models,
+        })
     )))
 }
 
@@ -283,7 +320,8 @@ pub async fn open_state(body: &Value) -> Result<Value> {
     let project_id = deployment_cid(&state.deployment_id);
     let project = get_project(&project_id).await?;
 
-    // check project price.
+    // check project price. AI project output and model token prices
+    // are converted to the units of this price when querying.
     let mut used_price = project.payg_price;
     if used_price < state.price_price {
         let now = Utc::now().timestamp();
@@ -600,6 +638,21 @@ pub async fn before_query_multiple_state(
     Ok((state, keyname, state_cache, mpqsa.is_inactive()))
 }
 
+/// check the channel can afford the units before query
+pub async fn check_multiple_state_afford(
+    state: &MultipleQueryState,
+    unit_times: u64,
+) -> Result<()> {
+    let (state_cache, _) = fetch_channel_cache(state.channel_id).await?;
+    let (used_amount, flag1) = state_cache.price.overflowing_mul(U256::from(unit_times));
+    let (local_next, flag2) = state_cache.spent.overflowing_add(used_amount);
+    if flag1 || flag2 || local_next > state_cache.total {
+        return Err(Error::Overflow(1080));
+    }
+
+    Ok(())
+}
+
 pub fn check_multiple_state_balance(
     state_cache: &StateCache,
     unit_times: u64,
@@ -0,0 +1,323 @@
+// This file is part of SubQuery.
+
+// Copyright (C) 2020-2024 SubQuery Pte Ltd authors & contributors
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Answer the proof-of-indexing challenge of SubQuery projects.
+//! The POI is read from the `_poi` entity of query service, and fallback
+//! to the admin endpoint of node, then signed by controller.
+
+use ethers::{
+    abi::{encode, Tokenizable},
+    signers::Signer,
+    types::Address,
+    utils::keccak256,
+};
+use serde_json::{json, Value};
+use std::time::{Duration, Instant, SystemTime};
+use subql_indexer_utils::{
+    error::Error,
+    request::{graphql_request, GraphQLQuery, REQUEST_CLIENT},
+    types::Result,
+};
+
+use crate::account::ACCOUNT;
+use crate::graphql::poi_query;
+use crate::metrics::{add_metrics_query, MetricsNetwork, MetricsQuery};
+use crate::primitives::POI_REQUEST_TIMEOUT;
+use crate::project::{Project, ProjectType};
+
+/// the internal endpoints of node which maybe serve the poi
+const ADMIN_ENDPOINTS: [&str; 4] = [
+    "adminEndpoint",
+    "admin-endpoint",
+    "nodeEndpoint",
+    "index-node-endpoint",
+];
+
+/// get the signed poi of project at the block height
+pub async fn project_poi(project: &Project, height: u64, network: MetricsNetwork) -> Result<Value> {
+    if !matches!(project.ptype, ProjectType::Subquery) {
+        return Err(Error::InvalidRequest(1073));
+    }
+
+    let now = Instant::now();
+    let res = fetch_poi(project, height).await;
+    let time = now.elapsed().as_millis() as u64;
+    add_metrics_query(
+        project.id.clone(),
+        Some(time),
+        MetricsQuery::Free,
+        network,
+        res.is_ok(),
+    );
+
+    sign_poi(project, height, res?).await
+}
+
+async fn fetch_poi(project: &Project, height: u64) -> Result<Value> {
+    let endpoint = project.endpoint("default", true)?;
+    for bigint in [true, false] {
+        let query = GraphQLQuery::query(&poi_query(height, bigint));
+        if let Ok(res) = graphql_request(&endpoint.endpoint, &query).await {
+            if let Some(poi) = res.pointer("/data/_poi").filter(|v| is_poi(v)) {
+                return Ok(poi.clone());
+            }
+        }
+    }
+
+    for key in ADMIN_ENDPOINTS {
+        if let Ok(admin) = project.endpoint(key, false) {
+            let url = format!("{}/poi/{}", admin.endpoint.trim_end_matches('/'), height);
+            match admin_poi(url).await {
+                Ok(poi) => return Ok(poi),
+                Err(err) => warn!("Project {} poi from {} failure: {:?}", project.id, key, err),
+            }
+        }
+    }
+
+    Err(Error::InvalidRequest(1074))
+}
+
+/// admin return the poi list or single poi
+async fn admin_poi(url: String) -> Result<Value> {
+    let res = REQUEST_CLIENT
+        .get(url)
+        .timeout(Duration::from_secs(POI_REQUEST_TIMEOUT))
+        .send()
+        .await
+        .map_err(|_| Error::InvalidRequest(1074))?;
+    let data: Value = res.json().await.map_err(|_| Error::InvalidRequest(1074))?;
+    let poi = match data {
+        Value::Array(mut list) if !list.is_empty() => list.remove(0),
+        v => v,
+    };
+    if is_poi(&poi) {
+        Ok(poi)
+    } else {
+        Err(Error::InvalidRequest(1074))
+    }
+}
+
+fn is_poi(v: &Value) -> bool {
+    v.get("hash").map(|h| !h.is_null()).unwrap_or(false)
+}
+
+/// the poi fields of string, null is empty
+fn poi_field(poi: &Value, key: &str) -> String {
+    poi[key].as_str().unwrap_or("").to_owned()
+}
+
+/// the signed message of poi, all the returned poi fields are signed
+fn poi_hash(
+    indexer: Address,
+    deployment: &str,
+    height: u64,
+    poi: &Value,
+    timestamp: u64,
+) -> [u8; 32] {
+    let payload = encode(&[
+        indexer.into_token(),
+        deployment.to_owned().into_token(),
+        height.into_token(),
+        poi_field(poi, "hash").into_token(),
+        poi_field(poi, "parentHash").into_token(),
+        poi_field(poi, "operationHashRoot").into_token(),
+        poi_field(poi, "chainBlockHash").into_token(),
+        timestamp.into_token(),
+    ]);
+    keccak256(payload)
+}
+
+async fn sign_poi(project: &Project, height: u64, poi: Value) -> Result<Value> {
+    let timestamp: u64 = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    let lock = ACCOUNT.read().await;
+    let controller = lock.controller.clone();
+    let controller_address = lock.controller_address();
+    let indexer = lock.indexer;
+    drop(lock);
+
+    let hash = poi_hash(indexer, &project.id, height, &poi, timestamp);
+    let sign = controller
+        .sign_message(hash)
+        .await
+        .map_err(|_| Error::InvalidSignature(1041))?;
+
+    Ok(json!({
+        "indexer": format!("{:?}", indexer),
+        "controller": format!("{:?}", controller_address),
+        "deploymentId": project.id,
+        "height": height,
+        "hash": poi_field(&poi, "hash"),
+        "parentHash": poi_field(&poi, "parentHash"),
+        "chainBlockHash": poi_field(&poi, "chainBlockHash"),
+        "operationHashRoot": poi_field(&poi, "operationHashRoot"),
+        "timestamp": timestamp,
+        "signature": sign.to_string(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::project::{Endpoint, ProjectType, RpcMainfest};
+    use axum::{
+        extract::Path,
+        routing::{get, post},
+        Json, Router,
+    };
+    use ethers::types::{Signature, U256};
+    use std::collections::HashMap;
+    use std::str::FromStr;
+
+    fn mock_poi(height: u64) -> Value {
+        json!({
+            "id": height.to_string(),
+            "hash": "0x1111",
+            "parentHash": "0x2222",
+            "chainBlockHash": "0x3333",
+            "operationHashRoot": "0x4444",
+        })
+    }
+
+    /// start a local query service, response the `_poi` only for the id style,
+    /// and the admin endpoint response the poi list when enabled
+    async fn mock_service(bigint: Option<bool>, admin: bool) -> String {
+        let app = Router::new()
+            .route(
+                "/",
+                post(move |Json(req): Json<Value>| async move {
+                    let query = req["query"].as_str().unwrap_or("");
+                    let is_bigint = query.contains("_poi(id: \"");
+                    let poi = match bigint {
+                        Some(b) if b == is_bigint => mock_poi(100),
+                        _ => Value::Null,
+                    };
+                    Json(json!({ "data": { "_poi": poi } }))
+                }),
+            )
+            .route(
+                "/poi/:height",
+                get(move |Path(height): Path<u64>| async move {
+                    if admin {
+                        Json(json!([mock_poi(height)]))
+                    } else {
+                        Json(json!([]))
+                    }
+                }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}", addr)
+    }
+
+    fn mock_project(ptype: ProjectType, url: String) -> Project {
+        let mut endpoints = HashMap::new();
+        for (key, is_internal) in [("default", false), ("adminEndpoint", true)] {
+            endpoints.insert(
+                key.to_owned(),
+                Endpoint {
+                    endpoint: url.clone(),
+                    is_internal,
+                    is_ws: false,
+                    rpc_family: vec![],
+                },
+            );
+        }
+        Project {
+            id: "QmMock".to_owned(),
+            ptype,
+            endpoints,
+            rate_limit: None,
+            db_size: None,
+            payg_price: U256::zero(),
+            payg_token: Address::zero(),
+            payg_expiration: 0,
+            payg_overflow: 0,
+            payg_output_price: U256::zero(),
+            payg_model_prices: HashMap::new(),
+        }
+    }
+
+    /// check the returned poi is signed by controller
+    async fn assert_signed(res: &Value, height: u64) {
+        assert_eq!(res["height"], height);
+        assert_eq!(res["hash"], "0x1111");
+        assert_eq!(res["parentHash"], "0x2222");
+        assert_eq!(res["chainBlockHash"], "0x3333");
+        assert_eq!(res["operationHashRoot"], "0x4444");
+
+        let indexer: Address = res["indexer"].as_str().unwrap().parse().unwrap();
+        let timestamp = res["timestamp"].as_u64().unwrap();
+        let hash = poi_hash(indexer, "QmMock", height, res, timestamp);
+        let sign = Signature::from_str(res["signature"].as_str().unwrap()).unwrap();
+        let controller = ACCOUNT.read().await.controller_address();
+        assert_eq!(sign.recover(&hash[..]).unwrap(), controller);
+
+        // the tampered fields cannot pass
+        let mut tampered = res.clone();
+        tampered["operationHashRoot"] = json!("0x5555");
+        let hash = poi_hash(indexer, "QmMock", height, &tampered, timestamp);
+        assert_ne!(sign.recover(&hash[..]).unwrap(), controller);
+    }
+
+    #[tokio::test]
+    async fn graphql_bigint_poi() {
+        let url = mock_service(Some(true), false).await;
+        let project = mock_project(ProjectType::Subquery, url);
+        let res = project_poi(&project, 100, MetricsNetwork::HTTP)
+            .await
+            .unwrap();
+        assert_signed(&res, 100).await;
+    }
+
+    #[tokio::test]
+    async fn graphql_int_poi() {
+        let url = mock_service(Some(false), false).await;
+        let project = mock_project(ProjectType::Subquery, url);
+        let res = project_poi(&project, 100, MetricsNetwork::HTTP)
+            .await
+            .unwrap();
+        assert_signed(&res, 100).await;
+    }
+
+    #[tokio::test]
+    async fn admin_poi_fallback() {
+        let url = mock_service(None, true).await;
+        let project = mock_project(ProjectType::Subquery, url);
+        let res = project_poi(&project, 120, MetricsNetwork::HTTP)
+            .await
+            .unwrap();
+        assert_signed(&res, 120).await;
+    }
+
+    #[tokio::test]
+    async fn poi_errors() {
+        let url = mock_service(None, false).await;
+        let project = mock_project(ProjectType::Subquery, url.clone());
+        let res = project_poi(&project, 100, MetricsNetwork::HTTP).await;
+        assert!(matches!(res, Err(Error::InvalidRequest(1074))));
+
+        let project = mock_project(ProjectType::RpcEvm(RpcMainfest::default()), url);
+        let res = project_poi(&project, 100, MetricsNetwork::HTTP).await;
+        assert!(matches!(res, Err(Error::InvalidRequest(1073))));
+    }
+}
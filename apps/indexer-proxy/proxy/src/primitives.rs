@@ -25,6 +25,27 @@ pub const P2P_BROADCAST_HEALTHY_TIME: u64 = 3600;
 /// report metrics time: 20min = 1200s
 pub const P2P_METRICS_TIME: u64 = 1200;
 
+/// timeout of p2p request-response, include the query time: 60s
+pub const P2P_REQUEST_TIMEOUT: u64 = 60;
+
+/// resend the telemetry when metrics node not ack in: 30s
+pub const P2P_OUTBOX_TIMEOUT: u64 = 30;
+
+/// republish the deployments and price records to DHT: 12h = 43200s
+pub const P2P_PROVIDE_TIME: u64 = 43200;
+
+/// max age of gossip healthy announcement: 5min = 300s
+pub const P2P_GOSSIP_MAX_AGE: i64 = 300;
+
+/// close the outbound connection not used by DHT, gossip or requests after: 2min = 120s
+pub const P2P_IDLE_CONNECTION_TIME: u64 = 120;
+
+/// cache the verified attestation of peer: 10min = 600s
+pub const P2P_ATTESTATION_CACHE_TIME: i64 = 600;
+
+/// max age of the attestation, it is republished with the deployments: 24h = 86400s
+pub const P2P_ATTESTATION_MAX_AGE: i64 = 86400;
+
 /// report metrics status time: 30min = 1800s
 // pub const P2P_METRICS_STATUS_TIME: u64 = 1800;
 
@@ -48,3 +69,60 @@ pub const SUBSCRIBER_LOOP_TIME: u64 = 120;
 
 /// loop refresh whitelist time: 30min = 1800s
 pub const WHITELIST_REFRESH_TIME: u64 = 1800;
+
+/// loop check the metadata cache need refresh time: 5s
+pub const METADATA_LOOP_TIME: u64 = 5;
+
+/// refresh the metadata of rpc projects time: 15s
+pub const METADATA_RPC_REFRESH_TIME: u64 = 15;
+
+/// refresh the metadata of subquery & subgraph projects time: 30s
+pub const METADATA_INDEXER_REFRESH_TIME: u64 = 30;
+
+/// refresh the metadata of ai projects time: 5min = 300s
+pub const METADATA_AI_REFRESH_TIME: u64 = 300;
+
+/// keep the metadata fetch failure, and not request upstream again: 3s
+pub const METADATA_FAILURE_TIME: i64 = 3;
+
+/// the lag data is stale after: 5min = 300s
+pub const LAG_STALE_TIME: i64 = 300;
+
+/// max concurrent deprioritized queries of lagging projects
+pub const LAG_DEPRIORITIZE_PERMITS: usize = 4;
+
+/// max waiting time of deprioritized query: 10s
+pub const LAG_DEPRIORITIZE_WAIT: u64 = 10;
+
+/// timeout of fetching poi from node admin endpoint: 10s
+pub const POI_REQUEST_TIMEOUT: u64 = 10;
+
+/// max pending requests (waiting response) in a websocket
+pub const WS_MAX_PENDING_REQUESTS: usize = 1000;
+
+/// the interval of proxy ping both sides of websocket: 30s
+pub const WS_PING_TIME: u64 = 30;
+
+/// close the websocket when no pong in: 90s
+pub const WS_PONG_TIMEOUT: u64 = 90;
+
+/// the payload of proxy ping, the pong will not forward
+pub const WS_PING_PAYLOAD: &[u8] = b"subql-proxy-ping";
+
+/// the max times of reconnecting the remote websocket
+pub const WS_RECONNECT_TIMES: u32 = 5;
+
+/// the max backoff of reconnecting the remote websocket: 30s
+pub const WS_RECONNECT_MAX_BACKOFF: u64 = 30;
+
+/// the interval of checking AI queue timeout and notifying position: 1s
+pub const AI_QUEUE_NOTIFY_TIME: u64 = 1;
+
+/// the output tokens reserved by payg AI query without max_tokens when unlimited
+pub const AI_RESERVED_OUTPUT_TOKENS: usize = 1024;
+
+/// not probe the tokenizer file of missing model again in: 5min = 300s
+pub const AI_TOKENIZER_MISS_TIME: u64 = 300;
+
+/// max cached missing models of tokenizer
+pub const AI_TOKENIZER_MISS_MAX: usize = 1000;
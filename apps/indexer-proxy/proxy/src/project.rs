@@ -19,10 +19,16 @@
 use crate::account::ACCOUNT;
 use crate::cli::{redis, COMMAND};
 use crate::graphql::project_mainfest;
+use crate::lag::check_lag;
 use crate::metadata::{
-    ai_metadata, rpc_evm_metadata, rpc_substrate_metadata, subgraph_metadata, subquery_metadata,
+    ai_metadata, rpc_bitcoin_metadata, rpc_cosmos_metadata, rpc_evm_metadata, rpc_near_metadata,
+    rpc_starknet_metadata, rpc_substrate_metadata, subgraph_metadata, subquery_metadata,
 };
 use crate::metrics::{add_metrics_query, update_metrics_projects, MetricsNetwork, MetricsQuery};
+use crate::mod_libp2p::{dht::provide_deployments, gossip::announce_prices};
+use crate::primitives::{
+    METADATA_AI_REFRESH_TIME, METADATA_INDEXER_REFRESH_TIME, METADATA_RPC_REFRESH_TIME,
+};
 // use crate::p2p::send;
 use chrono::Utc;
 use digest::Digest;
@@ -59,6 +65,10 @@ pub enum ProjectType {
     Subquery,
     RpcEvm(RpcMainfest),
     RpcSubstrate(RpcMainfest),
+    RpcCosmos(RpcMainfest),
+    RpcNear(RpcMainfest),
+    RpcStarknet(RpcMainfest),
+    RpcBitcoin(RpcMainfest),
     Subgraph,
     Ai,
 }
@@ -225,6 +235,12 @@ impl RpcMainfest {
             Ok((1, 1))
         }
     }
+
+    // units of every notification, config as `eth_subscribe:newHeads`
+    pub fn subscription_units(&self, method: &str, kind: Option<&str>) -> u64 {
+        let key = format!("{}:{}", method, kind.unwrap_or(""));
+        self.compute_unit.get(&key).map(|cu| cu.value).unwrap_or(1)
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -247,6 +263,42 @@ pub struct Project {
     pub payg_token: Address,
     pub payg_expiration: u64,
     pub payg_overflow: u64,
+    /// AI project output (completion) token price, input token price is payg_price
+    pub payg_output_price: U256,
+    /// AI project special model prices, model => (input price, output price)
+    pub payg_model_prices: HashMap<String, (U256, U256)>,
+}
+
+/// The AI token prices of the model, convert to channel units by payg_price
+#[derive(Clone, Debug, Default)]
+pub struct AiPricing {
+    pub unit: U256,
+    pub input: U256,
+    pub output: U256,
+}
+
+impl AiPricing {
+    /// the channel units of input and output tokens, round up
+    pub fn units(&self, input_tokens: usize, output_tokens: usize) -> u64 {
+        if self.unit.is_zero() {
+            return (input_tokens + output_tokens) as u64;
+        }
+
+        let cost = self.input.saturating_mul(U256::from(input_tokens))
+            + self.output.saturating_mul(U256::from(output_tokens));
+        let (units, rem) = cost.div_mod(self.unit);
+        let units = if rem.is_zero() {
+            units
+        } else {
+            units + U256::one()
+        };
+
+        if units > U256::from(u64::MAX) {
+            u64::MAX
+        } else {
+            units.as_u64()
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -261,7 +313,12 @@ impl Project {
         match &self.ptype {
             // TODO if multiple in single query
             ProjectType::Subquery | ProjectType::Subgraph | ProjectType::Ai => Ok(((1, 1), 0)),
-            ProjectType::RpcEvm(m) | ProjectType::RpcSubstrate(m) => {
+            ProjectType::RpcEvm(m)
+            | ProjectType::RpcSubstrate(m)
+            | ProjectType::RpcCosmos(m)
+            | ProjectType::RpcNear(m)
+            | ProjectType::RpcStarknet(m)
+            | ProjectType::RpcBitcoin(m) => {
                 // parse the jsonrpc method
                 if let Ok(s) = serde_json::from_str::<SimpleJsonrpc>(query) {
                     let id =
@@ -307,6 +364,19 @@ impl Project {
         }
     }
 
+    /// the compute units of subscription notification, default is 1
+    pub fn subscription_units(&self, method: &str, kind: Option<&str>) -> u64 {
+        match &self.ptype {
+            ProjectType::RpcEvm(m)
+            | ProjectType::RpcSubstrate(m)
+            | ProjectType::RpcCosmos(m)
+            | ProjectType::RpcNear(m)
+            | ProjectType::RpcStarknet(m)
+            | ProjectType::RpcBitcoin(m) => m.subscription_units(method, kind),
+            _ => 1,
+        }
+    }
+
     pub fn endpoint<'a>(&'a self, ep_name: &str, no_internal: bool) -> Result<&'a Endpoint> {
         if let Some(end) = self.endpoints.get(ep_name) {
             if no_internal && end.is_internal {
@@ -322,7 +392,12 @@ impl Project {
     pub fn is_rpc_project(&self) -> bool {
         matches!(
             self.ptype,
-            ProjectType::RpcEvm(_) | ProjectType::RpcSubstrate(_)
+            ProjectType::RpcEvm(_)
+                | ProjectType::RpcSubstrate(_)
+                | ProjectType::RpcCosmos(_)
+                | ProjectType::RpcNear(_)
+                | ProjectType::RpcStarknet(_)
+                | ProjectType::RpcBitcoin(_)
         )
     }
 
@@ -334,12 +409,52 @@ impl Project {
         matches!(self.ptype, ProjectType::Subgraph)
     }
 
+    /// the refresh interval (seconds) of the cached metadata
+    pub fn metadata_refresh_time(&self) -> u64 {
+        match self.ptype {
+            ProjectType::Subquery | ProjectType::Subgraph => METADATA_INDEXER_REFRESH_TIME,
+            ProjectType::RpcEvm(_)
+            | ProjectType::RpcSubstrate(_)
+            | ProjectType::RpcCosmos(_)
+            | ProjectType::RpcNear(_)
+            | ProjectType::RpcStarknet(_)
+            | ProjectType::RpcBitcoin(_) => METADATA_RPC_REFRESH_TIME,
+            ProjectType::Ai => METADATA_AI_REFRESH_TIME,
+        }
+    }
+
+    /// the AI token prices of the model, output price is same as input when not set
+    pub fn ai_pricing(&self, model: &str) -> AiPricing {
+        let (input, output) = self
+            .payg_model_prices
+            .get(model)
+            .cloned()
+            .unwrap_or((self.payg_price, self.payg_output_price));
+        let output = if output.is_zero() { input } else { output };
+
+        AiPricing {
+            unit: self.payg_price,
+            input,
+            output,
+        }
+    }
+
     pub fn open_payg(&self) -> bool {
         self.payg_price > U256::zero() && self.payg_expiration > 0
     }
 
-    pub async fn metadata(&self, network: MetricsNetwork) -> Result<Value> {
-        let mut metadata = match &self.ptype {
+    /// the payg price or token changed, need announce again.
+    pub fn price_changed(&self, other: &Project) -> bool {
+        self.payg_price != other.payg_price
+            || self.payg_token != other.payg_token
+            || self.payg_expiration != other.payg_expiration
+            || self.payg_output_price != other.payg_output_price
+            || self.payg_model_prices != other.payg_model_prices
+    }
+
+    /// the project metadata fetched from upstream, without indexer signature.
+    pub async fn raw_metadata(&self, network: MetricsNetwork) -> Result<Value> {
+        let metadata = match &self.ptype {
             ProjectType::Subquery => subquery_metadata(&self, network).await?,
             ProjectType::RpcEvm(m) => {
                 let mut data = rpc_evm_metadata(&self, network).await?;
@@ -351,10 +466,35 @@ impl Project {
                 merge_json(&mut data, &m.json_values());
                 data
             }
+            ProjectType::RpcCosmos(m) => {
+                let mut data = rpc_cosmos_metadata(&self, network).await?;
+                merge_json(&mut data, &m.json_values());
+                data
+            }
+            ProjectType::RpcNear(m) => {
+                let mut data = rpc_near_metadata(&self, network).await?;
+                merge_json(&mut data, &m.json_values());
+                data
+            }
+            ProjectType::RpcStarknet(m) => {
+                let mut data = rpc_starknet_metadata(&self, network).await?;
+                merge_json(&mut data, &m.json_values());
+                data
+            }
+            ProjectType::RpcBitcoin(m) => {
+                let mut data = rpc_bitcoin_metadata(&self, network).await?;
+                merge_json(&mut data, &m.json_values());
+                data
+            }
             ProjectType::Subgraph => subgraph_metadata(&self, network).await?,
             ProjectType::Ai => ai_metadata(&self).await?,
         };
 
+        Ok(metadata)
+    }
+
+    /// sign the raw metadata with controller
+    pub async fn sign_metadata(&self, mut metadata: Value) -> Result<Value> {
         let timestamp: u64 = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap()
@@ -461,6 +601,14 @@ impl Project {
             None
         };
 
+        // paid query need check the project lag
+        let _lag_permit =
+            if payment == MetricsQuery::PAYG || payment == MetricsQuery::CloseAgreement {
+                check_lag(&self.id).await?
+            } else {
+                None
+            };
+
         let (d, s) = match self.ptype {
             ProjectType::Subquery | ProjectType::Subgraph => match serde_json::from_str(&body) {
                 Ok(query) => {
@@ -480,7 +628,11 @@ impl Project {
                 self.rpcquery_raw(body, endpoint, payment, network, no_sig, path)
                     .await?
             }
-            ProjectType::RpcSubstrate(_) => {
+            ProjectType::RpcSubstrate(_)
+            | ProjectType::RpcCosmos(_)
+            | ProjectType::RpcNear(_)
+            | ProjectType::RpcStarknet(_)
+            | ProjectType::RpcBitcoin(_) => {
                 self.rpcquery_raw(body, endpoint, payment, network, no_sig, path)
                     .await?
             }
@@ -585,12 +737,14 @@ async fn update_projects(deployments: Vec<Project>) {
         new_deployments.push(deployment.id.clone());
     }
 
+    let mut removes = vec![];
     for o in old_deployments.iter() {
         if new_deployments.contains(o) {
             continue;
         } else {
             // let gid = hash_to_group_id(o.as_bytes());
             lock.remove(o);
+            removes.push(o.clone());
             // project leave
             // tokio::spawn(async move {
             //     send("project-leave", vec![], gid).await;
@@ -598,8 +752,17 @@ async fn update_projects(deployments: Vec<Project>) {
         }
     }
 
+    let mut provides = vec![];
+    let mut prices = vec![];
     for n in deployments {
         let did = n.id.clone();
+        if !old_deployments.contains(&did) {
+            provides.push(did.clone());
+        }
+        let price_changed = lock.get(&did).map(|o| o.price_changed(&n)).unwrap_or(true);
+        if price_changed && n.open_payg() {
+            prices.push(did.clone());
+        }
         lock.insert(did.clone(), n);
         // project join
         // tokio::spawn(async move {
@@ -608,6 +771,16 @@ async fn update_projects(deployments: Vec<Project>) {
     }
 
     drop(lock);
+
+    // publish to DHT
+    if !provides.is_empty() || !removes.is_empty() {
+        tokio::spawn(provide_deployments(provides, removes));
+    }
+
+    // announce the new prices to gossip
+    if !prices.is_empty() {
+        tokio::spawn(announce_prices(prices));
+    }
 }
 
 pub async fn get_project(key: &str) -> Result<Project> {
@@ -660,6 +833,19 @@ pub struct ProjectItem {
     pub payg_overflow: u64,
     #[serde(rename = "dbSize")]
     pub db_size: Option<String>,
+    #[serde(rename = "outputPrice", default)]
+    pub payg_output_price: Option<String>,
+    #[serde(rename = "modelPrices", default)]
+    pub payg_model_prices: Option<Vec<ModelPriceItem>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ModelPriceItem {
+    model: String,
+    #[serde(rename = "price")]
+    input_price: String,
+    #[serde(rename = "outputPrice")]
+    output_price: Option<String>,
 }
 
 pub async fn handle_projects(projects: Vec<ProjectItem>) -> Result<()> {
@@ -684,6 +870,19 @@ pub async fn handle_projects(projects: Vec<ProjectItem>) -> Result<()> {
         let payg_token: Address = item.payg_token.parse().unwrap_or(Address::zero());
         let payg_overflow = item.payg_overflow.into();
         let payg_expiration = item.payg_expiration;
+        let payg_output_price = item
+            .payg_output_price
+            .and_then(|p| U256::from_dec_str(&p).ok())
+            .unwrap_or(U256::from(0));
+        let mut payg_model_prices = HashMap::new();
+        for mp in item.payg_model_prices.unwrap_or_default() {
+            let input = U256::from_dec_str(&mp.input_price).unwrap_or(payg_price);
+            let output = mp
+                .output_price
+                .and_then(|p| U256::from_dec_str(&p).ok())
+                .unwrap_or(U256::from(0));
+            payg_model_prices.insert(mp.model, (input, output));
+        }
 
         let rpc_mainfest =
             RpcMainfest::fetch(&url, item.project_type, &item.id, payg_overflow).await?;
@@ -729,6 +928,18 @@ pub async fn handle_projects(projects: Vec<ProjectItem>) -> Result<()> {
                     "solana" => {
                         ptype = ProjectType::RpcEvm(rpc_mainfest.clone());
                     }
+                    "cosmos" => {
+                        ptype = ProjectType::RpcCosmos(rpc_mainfest.clone());
+                    }
+                    "near" => {
+                        ptype = ProjectType::RpcNear(rpc_mainfest.clone());
+                    }
+                    "starknet" => {
+                        ptype = ProjectType::RpcStarknet(rpc_mainfest.clone());
+                    }
+                    "bitcoin" => {
+                        ptype = ProjectType::RpcBitcoin(rpc_mainfest.clone());
+                    }
                     _ => {}
                 }
             }
@@ -767,6 +978,8 @@ pub async fn handle_projects(projects: Vec<ProjectItem>) -> Result<()> {
             payg_token,
             payg_expiration,
             payg_overflow,
+            payg_output_price,
+            payg_model_prices,
         };
 
         new_projects.push(project);
@@ -44,18 +44,24 @@ use subql_indexer_utils::{
 };
 use tower_http::cors::{Any, CorsLayer};
 
-use crate::ai::api_stream;
-use crate::auth::{create_jwt, AuthQuery, AuthQueryLimit, Payload};
+use crate::ai::{api_models, api_query, api_stream, AiRoute};
+use crate::auth::{create_jwt, verify_auth, AuthQuery, AuthQueryLimit, Payload};
 use crate::cli::COMMAND;
 use crate::contracts::check_agreement_and_consumer;
+use crate::lag::get_lag;
+use crate::metadata::cached_metadata;
 use crate::metrics::{get_owner_metrics, MetricsNetwork, MetricsQuery};
+use crate::mod_libp2p::{dht::find_providers, network::EventLoop};
 use crate::payg::{
     extend_channel, fetch_channel_cache, merket_price, open_state, pay_channel,
     query_multiple_state, query_single_state, AuthPayg,
 };
-use crate::project::get_project;
+use crate::poi::project_poi;
+use crate::project::{get_project, Project};
 use crate::sentry_log::make_sentry_message;
-use crate::websocket::{connect_to_project_ws, handle_websocket, validate_project, QueryType};
+use crate::websocket::{
+    connect_to_project_ws, handle_websocket, validate_project, QueryType, WsOwner, WsProtocol,
+};
 use crate::{
     account::{get_indexer, indexer_healthy},
     auth::AuthWhitelistQuery,
@@ -87,6 +93,10 @@ pub async fn start_server(port: u16) {
         .route("/wl-query/:deployment", post(default_wl_query))
         .route("/wl-query/:deployment/:ep_name", post(wl_query))
         .route("/wl-query/:deployment/:ep_name", get(ws_wl_query))
+        .route(
+            "/wl-query/:deployment/v1/*route",
+            get(wl_ai_query).post(wl_ai_query),
+        )
         // `GET /payg-price` get the payg price
         .route("/payg-price", get(payg_price))
         // `POST /payg-open` goes to open a state channel for payg
@@ -95,6 +105,11 @@ pub async fn start_server(port: u16) {
         .route("/payg/:deployment", post(default_payg))
         .route("/payg/:deployment/:ep_name", post(payg_query))
         .route("/payg/:deployment/:ep_name", get(ws_payg_query))
+        // `POST /payg/Qm...955X/v1/chat/completions` goes to the OpenAI compatible AI routes
+        .route(
+            "/payg/:deployment/v1/*route",
+            get(payg_ai_query).post(payg_ai_query),
+        )
         // `POST /payg-extend/0x00...955X` goes to extend channel expiration
         .route("/payg-extend/:channel", post(payg_extend))
         // `GET /payg-state/0x00...955X` goes to get channel state
@@ -104,6 +119,10 @@ pub async fn start_server(port: u16) {
         .route("/payg-pay", post(payg_pay))
         // `Get /metadata/Qm...955X?block=100` goes to query the metadata
         .route("/metadata/:deployment", get(metadata_handler))
+        // `Get /poi/Qm...955X/100` goes to query the signed poi at the block height
+        .route("/poi/:deployment/:height", get(poi_handler))
+        // `Get /providers/Qm...955X` goes to find the p2p providers of deployment
+        .route("/providers/:deployment", get(providers_handler))
         .route("/metrics", get(metrics_handler))
         // `Get /healthy` goes to query the service in running success (response the indexer)
         .route("/healthy", get(healthy_handler))
@@ -170,7 +189,10 @@ async fn ep_wl_query(
     }))
     .unwrap_or("".to_owned());
 
-    let header = vec![("Content-Type", "application/json")];
+    let mut header = vec![("Content-Type", "application/json")];
+    if let Some(lag) = get_lag(&deployment).await {
+        header.push(("X-Indexer-Lag", lag.to_string().leak()));
+    }
 
     Ok(build_response(body, header))
 }
@@ -192,6 +214,7 @@ async fn wl_query(
 }
 
 async fn ws_wl_query(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     headers: HeaderMap,
     ws: WebSocketUpgrade,
     Path((deployment, ep_name)): Path<(String, String)>,
@@ -201,9 +224,29 @@ async fn ws_wl_query(
         return Error::AuthVerify(1004).into_response();
     };
 
-    ws_handler(headers, ws, deployment, ep_name, QueryType::Whitelist)
-        .await
-        .into_response()
+    let owners = vec![WsOwner::Ip(addr.ip().to_string())];
+    ws_handler(
+        headers,
+        ws,
+        deployment,
+        ep_name,
+        QueryType::Whitelist,
+        owners,
+    )
+    .await
+    .into_response()
+}
+
+async fn wl_ai_query(
+    AuthWhitelistQuery(deployment_id): AuthWhitelistQuery,
+    Path((deployment, route)): Path<(String, String)>,
+    body: String,
+) -> AxumResponse {
+    if deployment != deployment_id {
+        return Error::AuthVerify(1004).into_response();
+    };
+
+    ai_query(deployment, route, body, None).await
 }
 
 async fn generate_token(
@@ -273,13 +316,14 @@ async fn generate_token(
 
 async fn default_query(
     headers: HeaderMap,
-    AuthQuery(deployment_id): AuthQuery,
+    AuthQuery(deployment_id, auth): AuthQuery,
     Path(deployment): Path<String>,
     body: String,
 ) -> Result<Response<String>, Error> {
     ep_query_handler(
         headers,
         deployment_id,
+        auth,
         deployment,
         "default".to_owned(),
         body,
@@ -289,16 +333,17 @@ async fn default_query(
 
 async fn query_handler(
     headers: HeaderMap,
-    AuthQuery(deployment_id): AuthQuery,
+    AuthQuery(deployment_id, auth): AuthQuery,
     Path((deployment, ep_name)): Path<(String, String)>,
     body: String,
 ) -> Result<Response<String>, Error> {
-    ep_query_handler(headers, deployment_id, deployment, ep_name, body).await
+    ep_query_handler(headers, deployment_id, auth, deployment, ep_name, body).await
 }
 
 async fn ep_query_handler(
     mut headers: HeaderMap,
     deployment_id: String,
+    auth: String,
     deployment: String,
     ep_name: String,
     body: String,
@@ -320,6 +365,11 @@ async fn ep_query_handler(
     if endpoint.is_ws {
         return Err(Error::WebSocket(1315));
     }
+    // same compute units as websocket and p2p
+    if COMMAND.auth() {
+        let ((units, _), _) = project.compute_query_method(&body)?;
+        verify_auth(&auth, units).await?;
+    }
     let (data, signature, limit) = project
         .check_query(
             body.clone(),
@@ -384,23 +434,41 @@ async fn ep_query_handler(
         headers.push(("X-RateLimit-Limit-Second", t.to_string().leak()));
         headers.push(("X-RateLimit-Remaining-Second", (t - u).to_string().leak()));
     }
+    if let Some(lag) = get_lag(&deployment).await {
+        headers.push(("X-Indexer-Lag", lag.to_string().leak()));
+    }
 
     Ok(build_response(body, headers))
 }
 
 async fn ws_query(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     headers: HeaderMap,
     ws: WebSocketUpgrade,
     Path((deployment, ep_name)): Path<(String, String)>,
-    AuthQuery(deployment_id): AuthQuery,
+    AuthQuery(deployment_id, _): AuthQuery,
 ) -> impl IntoResponse {
     if COMMAND.auth() && deployment != deployment_id {
         return Error::AuthVerify(1004).into_response();
     };
 
-    ws_handler(headers, ws, deployment, ep_name, QueryType::CloseAgreement)
-        .await
-        .into_response()
+    let mut owners = vec![WsOwner::Ip(addr.ip().to_string())];
+    if let Some(auth) = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+    {
+        owners.extend(WsOwner::from_jwt(auth));
+    }
+    ws_handler(
+        headers,
+        ws,
+        deployment,
+        ep_name,
+        QueryType::CloseAgreement,
+        owners,
+    )
+    .await
+    .into_response()
 }
 
 async fn query_limit_handler(
@@ -484,7 +552,14 @@ async fn ep_payg_handler(
             Ok(p) => p,
             Err(e) => return e.into_response(),
         };
-        return payg_stream(endpoint.endpoint.clone(), v, state, false).await;
+        return payg_stream(
+            endpoint.endpoint.clone(),
+            AiRoute::ChatCompletions,
+            v,
+            project.clone(),
+            Some(state),
+        )
+        .await;
     }
 
     let (data, signature, state_data, limit) = match block.to_str() {
@@ -585,21 +660,94 @@ async fn ep_payg_handler(
         headers.push(("X-RateLimit-Limit-Second", t.to_string().leak()));
         headers.push(("X-RateLimit-Remaining-Second", (t - u).to_string().leak()));
     }
+    if let Some(lag) = get_lag(&deployment).await {
+        headers.push(("X-Indexer-Lag", lag.to_string().leak()));
+    }
 
     build_response(body, headers).into_response()
 }
 
+async fn payg_ai_query(
+    AuthPayg(auth): AuthPayg,
+    Path((deployment, route)): Path<(String, String)>,
+    body: String,
+) -> AxumResponse {
+    // OpenAI SDK send the api key (state) as bearer token
+    let auth = auth.strip_prefix("Bearer ").unwrap_or(&auth).to_owned();
+    let state = match MultipleQueryState::from_bs64(auth) {
+        Ok(p) => p,
+        Err(e) => return e.into_response(),
+    };
+
+    ai_query(deployment, route, body, Some(state)).await
+}
+
+/// the OpenAI compatible AI routes, no state is whitelist query
+async fn ai_query(
+    deployment: String,
+    route: String,
+    body: String,
+    state: Option<MultipleQueryState>,
+) -> AxumResponse {
+    let route = match AiRoute::from_path(&route) {
+        Ok(p) => p,
+        Err(e) => return e.into_response(),
+    };
+    let project = match get_project(&deployment).await {
+        Ok(p) => p,
+        Err(e) => return e.into_response(),
+    };
+    if !project.is_ai_project() {
+        return Error::InvalidRequest(1076).into_response();
+    }
+    let url = match project.endpoint("default", state.is_some()) {
+        Ok(p) => route.url(&p.endpoint),
+        Err(e) => return e.into_response(),
+    };
+
+    if matches!(route, AiRoute::Models) {
+        return match api_models(url).await {
+            Ok(v) => Json(v).into_response(),
+            Err(e) => e.into_response(),
+        };
+    }
+
+    let v = match serde_json::from_str::<Value>(&body).map_err(|_| Error::Serialize(1142)) {
+        Ok(p) => p,
+        Err(e) => return e.into_response(),
+    };
+    if v["stream"].as_bool().unwrap_or(false) && !matches!(route, AiRoute::Embeddings) {
+        return payg_stream(url, route, v, project, state).await;
+    }
+
+    match api_query(url, route, v, project, state).await {
+        Ok((data, state)) => {
+            let body = serde_json::to_string(&data).unwrap_or("".to_owned());
+            let mut headers = vec![("Content-Type", "application/json")];
+            if let Some(state) = &state {
+                headers.push(("X-Channel-State", state.as_str()));
+            }
+            build_response(body, headers).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
 async fn ws_payg_query(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     headers: HeaderMap,
     ws: WebSocketUpgrade,
     Path((deployment, ep_name)): Path<(String, String)>,
 ) -> impl IntoResponse {
+    // the channel owner will be limited when first query
+    let owners = vec![WsOwner::Ip(addr.ip().to_string())];
     ws_handler(
         headers,
         ws,
         deployment,
         ep_name,
         QueryType::PAYG(U256::zero(), U256::zero()),
+        owners,
     )
     .await
     .into_response()
@@ -724,19 +872,33 @@ fn process_sign(value: &mut serde_json::Map<String, serde_json::Value>, key: &st
 }
 
 async fn metadata_handler(Path(deployment): Path<String>) -> Result<Response<String>, Error> {
-    let body = get_project(&deployment)
-        .await?
-        .metadata(MetricsNetwork::HTTP)
-        .await
-        .map(Json)?;
+    let project = get_project(&deployment).await?;
+    let body = cached_metadata(&project).await.map(Json)?;
+    Ok(build_response(
+        body.to_string(),
+        vec![("Cache-control", "no-cache")],
+    ))
+}
+
+async fn poi_handler(
+    Path((deployment, height)): Path<(String, u64)>,
+) -> Result<Response<String>, Error> {
+    let project = get_project(&deployment).await?;
+    let body = project_poi(&project, height, MetricsNetwork::HTTP).await?;
     Ok(build_response(
         body.to_string(),
         vec![("Cache-control", "no-cache")],
     ))
 }
 
+async fn providers_handler(Path(deployment): Path<String>) -> Result<Json<Value>, Error> {
+    let providers = find_providers(&deployment).await?;
+    Ok(Json(providers))
+}
+
 async fn healthy_handler() -> Result<Json<Value>, Error> {
-    let info = indexer_healthy().await;
+    let mut info = indexer_healthy().await;
+    info["p2p"] = EventLoop::reachability().await;
     Ok(Json(info))
 }
 
@@ -788,6 +950,7 @@ async fn ws_handler(
     deployment: String,
     ep_name: String,
     query_type: QueryType,
+    owners: Vec<WsOwner>,
 ) -> impl IntoResponse {
     let endpoint = match validate_project(&deployment, &ep_name).await {
         Ok(ep) => ep,
@@ -803,49 +966,36 @@ async fn ws_handler(
         Err(e) => return e.into_response(),
     };
 
-    let remote_socket = if project.is_subgraph_project() {
-        let request = match tokio_tungstenite::tungstenite::http::Request::builder()
-            .method("GET")
-            .uri(endpoint)
-            .header(header::SEC_WEBSOCKET_PROTOCOL, "graphql-ws")
-            .header(header::SEC_WEBSOCKET_KEY, "graphql-ws-key")
-            .header(header::SEC_WEBSOCKET_VERSION, "13")
-            .header(header::HOST, "localhost")
-            .header(header::CONNECTION, "Upgrade")
-            .header(header::UPGRADE, "websocket")
-            .body(())
-        {
-            Ok(request) => request,
-            Err(_) => return Error::WebSocket(1300).into_response(),
-        };
-
-        match tokio_tungstenite::connect_async(request).await {
-            Ok((socket, _)) => socket,
-            Err(_e) => {
-                // info!("_e: {:?}", _e);
-                return Error::WebSocket(1308).into_response();
-            }
-        }
+    let protocol = if project.is_subgraph_project() {
+        WsProtocol::negotiate(headers.get(header::SEC_WEBSOCKET_PROTOCOL))
     } else {
-        match connect_to_project_ws(endpoint).await {
-            Ok(socket) => socket,
-            Err(e) => return e.into_response(),
-        }
+        WsProtocol::Jsonrpc
+    };
+
+    let remote = match connect_to_project_ws(endpoint, protocol).await {
+        Ok(remote) => remote,
+        Err(e) => return e.into_response(),
+    };
+
+    let ws = match protocol.subprotocol() {
+        Some(subprotocol) => ws.protocols([subprotocol]),
+        None => ws,
     };
 
     // Handle WebSocket connection
     ws.on_upgrade(move |socket: WebSocket| {
-        handle_websocket(remote_socket, socket, deployment, query_type, no_sig)
+        handle_websocket(remote, socket, deployment, query_type, no_sig, owners)
     })
 }
 
 async fn payg_stream(
-    endpoint: String,
+    url: String,
+    route: AiRoute,
     v: Value,
-    state: MultipleQueryState,
-    is_test: bool,
+    project: Project,
+    state: Option<MultipleQueryState>,
 ) -> AxumResponse {
-    let mut res = StreamBodyAs::text(api_stream(endpoint, v, state, is_test)).into_response();
+    let mut res = StreamBodyAs::text(api_stream(url, route, v, project, state)).into_response();
     res.headers_mut()
         .insert("Content-Type", "text/event-stream".parse().unwrap());
     res.headers_mut()
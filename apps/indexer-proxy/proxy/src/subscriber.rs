@@ -22,7 +22,9 @@ use subql_indexer_utils::request::{graphql_request, GraphQLQuery};
 
 use crate::account::handle_account;
 use crate::cli::COMMAND;
-use crate::graphql::{ACCOUNT_QUERY, CHANNEL_QUERY, PAYG_QUERY, PROJECT_QUERY, VERSION_QUERY};
+use crate::graphql::{
+    ACCOUNT_QUERY, CHANNEL_QUERY, PAYG_AI_QUERY, PAYG_QUERY, PROJECT_QUERY, VERSION_QUERY,
+};
 use crate::metrics::COORDINATOR_VERSION;
 use crate::payg::handle_channel;
 use crate::primitives::{SUBSCRIBER_INIT_TIME, SUBSCRIBER_LOOP_TIME};
@@ -86,6 +88,7 @@ pub fn subscribe() {
             tokio::time::sleep(std::time::Duration::from_secs(next_time)).await;
             let query = GraphQLQuery::query(PROJECT_QUERY);
             let payg = GraphQLQuery::query(PAYG_QUERY);
+            let payg_ai = GraphQLQuery::query(PAYG_AI_QUERY);
             let mut raw_projects = HashMap::new();
             let mut raw_paygs = HashMap::new();
             if let Ok(value) = graphql_request(&url, &query).await {
@@ -108,6 +111,18 @@ pub fn subscribe() {
                     }
                 }
             }
+            if let Ok(value) = graphql_request(&url, &payg_ai).await {
+                if let Some(items) = value.pointer("/data/getAlivePaygs") {
+                    if let Some(paygs) = items.as_array() {
+                        for payg in paygs {
+                            let pid = payg["id"].as_str().unwrap_or("");
+                            if let Some(raw) = raw_paygs.get_mut(pid) {
+                                merge(raw, payg);
+                            }
+                        }
+                    }
+                }
+            }
 
             if !raw_projects.is_empty() {
                 next_time = SUBSCRIBER_LOOP_TIME;
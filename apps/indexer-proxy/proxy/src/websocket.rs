@@ -1,33 +1,56 @@
-use axum::extract::ws::{CloseFrame, Message, WebSocket};
+use axum::{
+    extract::ws::{CloseFrame, Message, WebSocket},
+    http::{header, HeaderValue},
+};
 use base64::{engine::general_purpose, Engine as _};
 use chrono::Utc;
 use ethers::types::U256;
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
-use serde_json::{from_str, json};
+use serde_json::{from_str, json, Value};
+use std::collections::HashMap;
 use std::result::Result;
 use std::sync::Arc;
-use tokio::{net::TcpStream, select};
+use std::time::Instant;
+use tokio::{
+    net::TcpStream,
+    select,
+    sync::Mutex,
+    task::JoinHandle,
+    time::{interval, sleep, Duration},
+};
 use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
 
 use futures_util::{sink::SinkExt, StreamExt};
 use subql_indexer_utils::{error::Error, payg::MultipleQueryState};
-use tokio_tungstenite::tungstenite::protocol::Message as TMessage;
+use tokio_tungstenite::tungstenite::{client::IntoClientRequest, protocol::Message as TMessage};
 
 use crate::{
     account::ACCOUNT,
-    auth::{verify_auth_ws, AuthWhitelistQuery},
+    auth::{jwt_agreement, verify_auth, AuthWhitelistQuery},
     cli::{redis, COMMAND},
-    metrics::{add_metrics_query, MetricsNetwork, MetricsQuery},
+    metrics::{add_metrics_query, update_metrics_ws, MetricsNetwork, MetricsQuery},
     payg::{
         before_query_multiple_state, channel_id_to_keyname, check_multiple_state_balance,
         fetch_channel_cache, post_query_multiple_state, StateCache,
     },
+    primitives::{
+        WS_MAX_PENDING_REQUESTS, WS_PING_PAYLOAD, WS_PING_TIME, WS_PONG_TIMEOUT,
+        WS_RECONNECT_MAX_BACKOFF, WS_RECONNECT_TIMES,
+    },
     project::{get_project, Project},
     response::sign_response,
 };
 
 pub type SocketConnection = WebSocketStream<MaybeTlsStream<TcpStream>>;
 
+/// the upstream socket with the endpoint & protocol, used to reconnect
+pub struct RemoteSocket {
+    pub endpoint: String,
+    pub protocol: WsProtocol,
+    pub socket: SocketConnection,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct ReceivedMessage {
     body: String,
@@ -50,18 +73,204 @@ pub enum QueryType {
     Whitelist,
 }
 
+/// the message protocol of the websocket
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum WsProtocol {
+    /// jsonrpc of rpc projects
+    Jsonrpc,
+    /// legacy `graphql-ws` (subscriptions-transport-ws)
+    GraphqlWs,
+    /// `graphql-transport-ws` (graphql-ws library)
+    GraphqlTransportWs,
+}
+
+impl WsProtocol {
+    /// choose the first supported subprotocol which client requested,
+    /// default is the legacy `graphql-ws`
+    pub fn negotiate(requested: Option<&HeaderValue>) -> Self {
+        let requested = requested.and_then(|v| v.to_str().ok()).unwrap_or("");
+        for name in requested.split(',').map(|s| s.trim()) {
+            match name {
+                "graphql-transport-ws" => return WsProtocol::GraphqlTransportWs,
+                "graphql-ws" => return WsProtocol::GraphqlWs,
+                _ => {}
+            }
+        }
+        WsProtocol::GraphqlWs
+    }
+
+    pub fn subprotocol(&self) -> Option<&'static str> {
+        match self {
+            WsProtocol::Jsonrpc => None,
+            WsProtocol::GraphqlWs => Some("graphql-ws"),
+            WsProtocol::GraphqlTransportWs => Some("graphql-transport-ws"),
+        }
+    }
+
+    fn is_graphql(&self) -> bool {
+        *self != WsProtocol::Jsonrpc
+    }
+
+    /// client start a subscription
+    fn is_subscribe(&self, msg_type: &str) -> bool {
+        match self {
+            WsProtocol::Jsonrpc => false,
+            WsProtocol::GraphqlWs => msg_type == "start",
+            WsProtocol::GraphqlTransportWs => msg_type == "subscribe",
+        }
+    }
+
+    /// the result data of subscription, need billing and signing
+    fn is_data(&self, msg_type: &str) -> bool {
+        match self {
+            WsProtocol::Jsonrpc => false,
+            WsProtocol::GraphqlWs => msg_type == "data",
+            WsProtocol::GraphqlTransportWs => msg_type == "next",
+        }
+    }
+
+    /// subscription finished by client or remote
+    fn is_finished(&self, msg_type: &str) -> bool {
+        matches!(msg_type, "complete" | "stop" | "error")
+    }
+}
+
+/// the owner of websocket, used to limit the concurrent sockets
+#[derive(Clone, Debug)]
+pub enum WsOwner {
+    /// jwt auth token
+    Token(String),
+    /// close agreement id
+    Agreement(String),
+    /// payg state channel id
+    Channel(String),
+    /// consumer ip address
+    Ip(String),
+}
+
+impl WsOwner {
+    /// the owners of the jwt token (token & agreement)
+    pub fn from_jwt(authorisation: &str) -> Vec<WsOwner> {
+        // the signature part is unique for every token
+        let token = authorisation.rsplit('.').next().unwrap_or("").to_owned();
+        let mut owners = vec![WsOwner::Token(token)];
+        if let Some(agreement) = jwt_agreement(authorisation) {
+            owners.push(WsOwner::Agreement(agreement));
+        }
+        owners
+    }
+
+    fn key(&self) -> String {
+        match self {
+            WsOwner::Token(t) => format!("token-{}", t),
+            WsOwner::Agreement(a) => format!("agreement-{}", a),
+            WsOwner::Channel(c) => format!("channel-{}", c),
+            WsOwner::Ip(i) => format!("ip-{}", i),
+        }
+    }
+
+    /// max concurrent sockets, 0 is unlimited
+    fn limit(&self) -> usize {
+        match self {
+            WsOwner::Token(_) => COMMAND.ws_max_per_token,
+            WsOwner::Agreement(_) => COMMAND.ws_max_per_agreement,
+            WsOwner::Channel(_) => COMMAND.ws_max_per_channel,
+            WsOwner::Ip(_) => COMMAND.ws_max_per_ip,
+        }
+    }
+}
+
+/// owner key => active sockets
+static WS_ACTIVE: Lazy<Mutex<HashMap<String, usize>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// acquire the socket slots of all owners, failure if any owner exceeds the limit
+async fn acquire_owners(owners: &[WsOwner]) -> Result<(), Error> {
+    let mut lock = WS_ACTIVE.lock().await;
+    for owner in owners {
+        let limit = owner.limit();
+        if limit > 0 && lock.get(&owner.key()).copied().unwrap_or(0) >= limit {
+            return Err(Error::WebSocket(1320));
+        }
+    }
+    for owner in owners {
+        *lock.entry(owner.key()).or_default() += 1;
+    }
+    drop(lock);
+    Ok(())
+}
+
+async fn release_owners(owners: &[WsOwner]) {
+    let mut lock = WS_ACTIVE.lock().await;
+    for owner in owners {
+        let key = owner.key();
+        if let Some(count) = lock.get_mut(&key) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                lock.remove(&key);
+            }
+        }
+    }
+    drop(lock);
+}
+
+/// the request waiting the response from remote
+struct PendingRequest {
+    /// the request id of client (ids array when batch), response the error with it when lost
+    id: Value,
+    units: u64,
+    method: String,
+    /// subscription type, e.g. newHeads, logs
+    kind: Option<String>,
+    /// the raw subscribe request, replay it when reconnected
+    body: String,
+}
+
 struct WebSocketConnection {
     deployment: String,
     client_socket: WebSocket,
     remote_socket: SocketConnection,
+    endpoint: String,
+    /// closing by proxy or client, no reconnect
+    closing: bool,
     order_id: U256,
     query_type: QueryType,
+    protocol: WsProtocol,
     no_sig: bool,
+    /// the last verified auth token of close agreement, used to charge the notifications
+    auth: String,
+    /// request id => pending request
+    pending: HashMap<String, PendingRequest>,
+    /// subscription id => units of every notification
+    subscriptions: HashMap<String, u64>,
+    /// the owners which acquired the socket slots
+    owners: Vec<WsOwner>,
+    /// the payg channel which acquired the socket slot, only once per connection
+    channel_owner: Option<WsOwner>,
+    started_at: Instant,
+    /// last data message from client or remote
+    active_at: Instant,
+    client_pong_at: Instant,
+    remote_pong_at: Instant,
+    /// client subscription id => (client subscription id, subscribe request)
+    replays: HashMap<String, (Value, String)>,
+    /// upstream subscription id => client subscription id, changed after reconnected
+    remap: HashMap<String, Value>,
+    /// replay request id => client subscription id
+    replaying: HashMap<String, Value>,
+    /// graphql connection_init message
+    init_message: Option<String>,
+    /// skip the graphql connection_ack of replay
+    awaiting_ack: bool,
+    /// the reconnect task, remote is unavailable until it finished
+    reconnecting: Option<JoinHandle<Result<SocketConnection, Error>>>,
+    /// the messages to remote when reconnecting, send them after reconnected
+    queued: Vec<String>,
+    disconnected_at: i64,
 }
 
 impl WebSocketConnection {
     fn new(
-        remote_socket: SocketConnection,
+        remote: RemoteSocket,
         client_socket: WebSocket,
         query_type: QueryType,
         deployment: &str,
@@ -70,23 +279,294 @@ impl WebSocketConnection {
         WebSocketConnection {
             deployment: deployment.to_string(),
             client_socket,
-            remote_socket,
+            remote_socket: remote.socket,
+            endpoint: remote.endpoint,
+            closing: false,
             query_type,
+            protocol: remote.protocol,
             no_sig,
+            auth: String::default(),
             order_id: U256::zero(),
+            pending: HashMap::new(),
+            subscriptions: HashMap::new(),
+            owners: vec![],
+            channel_owner: None,
+            started_at: Instant::now(),
+            active_at: Instant::now(),
+            client_pong_at: Instant::now(),
+            remote_pong_at: Instant::now(),
+            replays: HashMap::new(),
+            remap: HashMap::new(),
+            replaying: HashMap::new(),
+            init_message: None,
+            awaiting_ack: false,
+            reconnecting: None,
+            queued: vec![],
+            disconnected_at: 0,
+        }
+    }
+
+    /// the remote closed, start reconnecting if enabled, return false if closed
+    async fn on_remote_closed(&mut self) -> bool {
+        if self.closing {
+            return false;
+        }
+        // the requests in flight are lost
+        self.fail_pending().await;
+        if COMMAND.ws_reconnect {
+            self.disconnected_at = Utc::now().timestamp_millis();
+            self.remap.clear();
+            self.replaying.clear();
+
+            let (endpoint, protocol) = (self.endpoint.clone(), self.protocol);
+            self.reconnecting = Some(tokio::spawn(reconnect_remote(endpoint, protocol)));
+            return true;
         }
+        self.closing = true;
+        let _ = self.close_client(Some(Error::WebSocket(1309))).await;
+        false
+    }
+
+    /// response the error to the requests in flight, the client will not wait them,
+    /// the graphql subscriptions are kept and replayed when reconnected
+    async fn fail_pending(&mut self) {
+        if self.protocol.is_graphql() {
+            return;
+        }
+        let error = Error::WebSocket(1321);
+        let (_, code, reason) = error.to_status_message();
+        let error_item = |id: Value| {
+            json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": code, "message": reason }
+            })
+        };
+        for (_, request) in std::mem::take(&mut self.pending) {
+            let error_msg = match request.id {
+                Value::Array(ids) => Value::Array(ids.into_iter().map(error_item).collect()),
+                id => error_item(id),
+            };
+            if self
+                .client_socket
+                .send(Message::Text(error_msg.to_string()))
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    }
+
+    /// the reconnect task finished, replay the subscriptions and the queued messages,
+    /// return false if closed
+    async fn on_reconnected(&mut self, res: Result<SocketConnection, Error>) -> bool {
+        self.reconnecting = None;
+        let res = match res {
+            Ok(socket) => self.replay(socket).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            debug!("Reconnect remote failure: {:?}", e);
+            self.closing = true;
+            let _ = self.close_client(Some(Error::WebSocket(1309))).await;
+            return false;
+        }
+        true
+    }
+
+    /// replay the subscriptions to the new remote
+    async fn replay(&mut self, socket: SocketConnection) -> Result<(), Error> {
+        self.remote_socket = socket;
+        self.remote_pong_at = Instant::now();
+
+        if self.protocol.is_graphql() {
+            if let Some(init) = self.init_message.clone() {
+                self.awaiting_ack = true;
+                self.send_remote(init).await?;
+            }
+            // graphql subscription id is chosen by client, no remap,
+            // and the subscriptions when reconnecting are in the queue
+            let bodies: Vec<String> = self
+                .replays
+                .values()
+                .filter(|(_, b)| !self.queued.contains(b))
+                .map(|(_, b)| b.clone())
+                .collect();
+            for body in bodies {
+                self.send_remote(body).await?;
+            }
+        } else {
+            let replays: Vec<(String, Value, String)> = self
+                .replays
+                .iter()
+                .map(|(k, (sid, body))| (k.clone(), sid.clone(), body.clone()))
+                .collect();
+            for (key, sid, body) in replays {
+                let mut request: Value = from_str(&body).unwrap_or_default();
+                let replay_id = format!("subql-replay-{}", key);
+                request["id"] = json!(replay_id);
+                self.replaying.insert(replay_id, sid);
+                self.send_remote(request.to_string()).await?;
+            }
+        }
+
+        // tell client the gap of subscriptions
+        let subscriptions: Vec<Value> = self.replays.values().map(|(s, _)| s.clone()).collect();
+        let params = json!({
+            "disconnectedAt": self.disconnected_at,
+            "reconnectedAt": Utc::now().timestamp_millis(),
+            "subscriptions": subscriptions,
+        });
+        let notice = if self.protocol.is_graphql() {
+            json!({ "type": "proxy_reconnected", "payload": params })
+        } else {
+            json!({ "jsonrpc": "2.0", "method": "proxy_reconnected", "params": params })
+        };
+        self.send_msg(
+            notice.to_string().into_bytes(),
+            String::default(),
+            String::default(),
+            None,
+        )
+        .await?;
+
+        for body in std::mem::take(&mut self.queued) {
+            self.send_remote(body).await?;
+        }
+        Ok(())
+    }
+
+    /// the subscription id of upstream after reconnected
+    fn upstream_request(&self, body: String, request: &Value) -> String {
+        if self.remap.is_empty() || self.protocol.is_graphql() {
+            return body;
+        }
+        let method = request["method"].as_str().unwrap_or("");
+        if !method.ends_with("_unsubscribe") {
+            return body;
+        }
+        let client_sid = &request["params"][0];
+        match self.remap.iter().find(|(_, v)| *v == client_sid) {
+            Some((upstream_sid, _)) => {
+                let mut request = request.clone();
+                request["params"][0] = json!(upstream_sid);
+                request.to_string()
+            }
+            None => body,
+        }
+    }
+
+    /// map the replay response & notification to client, None if skip it
+    fn remap_response(&mut self, msg: String) -> Option<String> {
+        if self.protocol.is_graphql() {
+            if self.awaiting_ack {
+                let response: Value = from_str(&msg).unwrap_or_default();
+                if response["type"].as_str() == Some("connection_ack") {
+                    self.awaiting_ack = false;
+                    return None;
+                }
+            }
+            return Some(msg);
+        }
+        if self.remap.is_empty() && self.replaying.is_empty() {
+            return Some(msg);
+        }
+
+        let mut response: Value = from_str(&msg).unwrap_or_default();
+        if let Some(id) = message_id(&response) {
+            if let Some(client_sid) = self.replaying.remove(&id) {
+                if let Some(sid) = value_key(&response["result"]) {
+                    self.remap.insert(sid, client_sid);
+                }
+                return None;
+            }
+        }
+        if let Some(sid) = value_key(&response["params"]["subscription"]) {
+            if let Some(client_sid) = self.remap.get(&sid) {
+                response["params"]["subscription"] = client_sid.clone();
+                return Some(response.to_string());
+            }
+        }
+        Some(msg)
+    }
+
+    /// check the timeouts and liveness, return the close reason
+    fn check_alive(&self) -> Option<Error> {
+        if COMMAND.ws_max_lifetime > 0
+            && self.started_at.elapsed().as_secs() > COMMAND.ws_max_lifetime
+        {
+            return Some(Error::WebSocket(1318));
+        }
+        if COMMAND.ws_idle_timeout > 0
+            && self.active_at.elapsed().as_secs() > COMMAND.ws_idle_timeout
+        {
+            return Some(Error::WebSocket(1317));
+        }
+        if self.client_pong_at.elapsed().as_secs() > WS_PONG_TIMEOUT
+            || (self.reconnecting.is_none()
+                && self.remote_pong_at.elapsed().as_secs() > WS_PONG_TIMEOUT)
+        {
+            return Some(Error::WebSocket(1319));
+        }
+        None
+    }
+
+    /// proxy pings both sides, the pongs with same payload will not forward
+    async fn ping(&mut self) -> Result<(), Error> {
+        self.client_socket
+            .send(Message::Ping(WS_PING_PAYLOAD.to_vec()))
+            .await
+            .map_err(|_| Error::WebSocket(1313))?;
+        if self.reconnecting.is_some() {
+            return Ok(());
+        }
+        self.remote_socket
+            .send(TMessage::Ping(WS_PING_PAYLOAD.to_vec()))
+            .await
+            .map_err(|_| Error::WebSocket(1314))?;
+        Ok(())
     }
 
     /// receive message from consumer
     async fn receive_text_msg(&mut self, raw_msg: &str) -> Result<(), Error> {
         let message = from_str::<ReceivedMessage>(raw_msg).map_err(|_| Error::WebSocket(1301))?;
         let ReceivedMessage { body, auth } = message;
-        let inactive = self.before_query_check(auth, &body).await?;
+        let request: Value = from_str(&body).unwrap_or_default();
+        let request_id = message_id(&request);
+
+        // graphql control messages, no auth & billing
+        let msg_type = request["type"].as_str().unwrap_or("");
+        if self.protocol.is_graphql() && !self.protocol.is_subscribe(msg_type) {
+            if msg_type == "connection_init" {
+                self.init_message = Some(body.clone());
+            }
+            if let (true, Some(id)) = (self.protocol.is_finished(msg_type), &request_id) {
+                self.pending.remove(id);
+                self.subscriptions.remove(id);
+                self.replays.remove(id);
+            }
+            return self.send_remote(body).await;
+        }
+        if request_id.is_some() && self.pending.len() >= WS_MAX_PENDING_REQUESTS {
+            return Err(Error::WebSocket(1316));
+        }
+
+        let (inactive, units) = self.before_query_check(auth, &body, &request_id).await?;
         if let Some(state) = inactive {
             self.send_msg(vec![], "".to_owned(), state, None).await?;
             return Ok(());
         }
+        self.track_request(&request, &body, request_id, units);
+        let body = self.upstream_request(body, &request);
+        self.send_remote(body).await
+    }
 
+    async fn send_remote(&mut self, body: String) -> Result<(), Error> {
+        if self.reconnecting.is_some() {
+            self.queued.push(body);
+            return Ok(());
+        }
         self.remote_socket
             .send(TMessage::Text(body))
             .await
@@ -94,9 +574,135 @@ impl WebSocketConnection {
         Ok(())
     }
 
+    /// record the request, and the response will be priced by it
+    fn track_request(
+        &mut self,
+        request: &Value,
+        body: &str,
+        request_id: Option<String>,
+        units: u64,
+    ) {
+        let Some(id) = request_id else {
+            return;
+        };
+        if self.protocol.is_graphql() {
+            // first data use the prepaid state, next data price by subscription
+            self.subscriptions.insert(id.clone(), units);
+            self.replays
+                .insert(id.clone(), (json!(id), body.to_owned()));
+            self.pending.insert(
+                id.clone(),
+                PendingRequest {
+                    id: json!(id),
+                    units,
+                    method: "subscribe".to_owned(),
+                    kind: None,
+                    body: String::default(),
+                },
+            );
+            return;
+        }
+
+        let first = first_message(request);
+        let method = first["method"].as_str().unwrap_or("").to_owned();
+        if method.ends_with("_unsubscribe") {
+            if let Some(sid) = value_key(&first["params"][0]) {
+                self.subscriptions.remove(&sid);
+                self.replays.remove(&sid);
+            }
+        }
+        let kind = first["params"][0].as_str().map(|s| s.to_owned());
+        let body = if method.ends_with("_subscribe") && !request.is_array() {
+            body.to_owned()
+        } else {
+            String::default()
+        };
+
+        self.pending.insert(
+            id,
+            PendingRequest {
+                id: match request {
+                    Value::Array(items) => items.iter().map(|item| item["id"].clone()).collect(),
+                    _ => first["id"].clone(),
+                },
+                units,
+                method,
+                kind,
+                body,
+            },
+        );
+    }
+
+    /// map the remote message to the request or subscription, return units & request id
+    async fn track_response(&mut self, msg: &str) -> (u64, Option<String>) {
+        let response: Value = from_str(msg).unwrap_or_default();
+        if self.protocol.is_graphql() {
+            let id = message_id(&response).unwrap_or_default();
+            if let Some(request) = self.pending.remove(&id) {
+                return (request.units, Some(id));
+            }
+            return (self.subscriptions.get(&id).copied().unwrap_or(1), None);
+        }
+
+        if let Some(id) = message_id(&response) {
+            if let Some(request) = self.pending.remove(&id) {
+                if request.method.ends_with("_subscribe") {
+                    let result = &first_message(&response)["result"];
+                    if let Some(sid) = value_key(result) {
+                        let units = match get_project(&self.deployment).await {
+                            Ok(project) => {
+                                project.subscription_units(&request.method, request.kind.as_deref())
+                            }
+                            Err(_) => 1,
+                        };
+                        self.subscriptions.insert(sid.clone(), units);
+                        if !request.body.is_empty() {
+                            self.replays.insert(sid, (result.clone(), request.body));
+                        }
+                    }
+                }
+                return (request.units, Some(id));
+            }
+        }
+
+        // subscription notification
+        if let Some(sid) = value_key(&response["params"]["subscription"]) {
+            return (self.subscriptions.get(&sid).copied().unwrap_or(1), None);
+        }
+
+        (1, None)
+    }
+
     /// send message to consumer
     async fn send_text_msg(&mut self, msg: String) -> Result<(), Error> {
-        let (state, limit) = self.post_query_sync().await?;
+        let Some(msg) = self.remap_response(msg) else {
+            return Ok(());
+        };
+
+        if self.protocol.is_graphql() {
+            let response: Value = from_str(&msg).unwrap_or_default();
+            let msg_type = response["type"].as_str().unwrap_or("");
+            if !self.protocol.is_data(msg_type) {
+                // control messages, no billing & signing
+                if let (true, Some(id)) =
+                    (self.protocol.is_finished(msg_type), message_id(&response))
+                {
+                    self.pending.remove(&id);
+                    self.subscriptions.remove(&id);
+                    self.replays.remove(&id);
+                }
+                return self
+                    .send_msg(msg.into_bytes(), String::default(), String::default(), None)
+                    .await;
+            }
+        }
+
+        let (units, request_id) = self.track_response(&msg).await;
+        if request_id.is_none() && !self.check_notification_auth(units).await {
+            // drop the over quota notification, keep the socket and other subscriptions
+            return Ok(());
+        }
+        let (state, limit) = self.post_query_sync(units, request_id).await?;
         let msg_data = msg.into_bytes();
 
         let signature = if self.no_sig {
@@ -123,6 +729,19 @@ impl WebSocketConnection {
         Ok(())
     }
 
+    /// the notification is pushed without client request, re-check the token expiry
+    /// and charge the units of agreement, false when the notification need be dropped
+    async fn check_notification_auth(&mut self, units: u64) -> bool {
+        if self.query_type != QueryType::CloseAgreement || !COMMAND.auth() || self.auth.is_empty() {
+            return true;
+        }
+        if let Err(e) = verify_auth(&self.auth, units).await {
+            self.send_error_msg(&e).await;
+            return false;
+        }
+        true
+    }
+
     async fn send_msg(
         &mut self,
         msg: Vec<u8>,
@@ -157,8 +776,8 @@ impl WebSocketConnection {
         Ok(())
     }
 
-    async fn send_error_msg(&mut self, error: Error) {
-        let id = match &error {
+    async fn send_error_msg(&mut self, error: &Error) {
+        let id = match error {
             Error::Jsonrpc(id, _) => *id,
             _ => 0,
         };
@@ -190,6 +809,11 @@ impl WebSocketConnection {
 
     async fn close_remote(&mut self) -> Result<(), Error> {
         debug!("CLOSE REMOTE");
+        self.closing = true;
+        if let Some(task) = self.reconnecting.take() {
+            task.abort();
+            return Ok(());
+        }
         self.remote_socket
             .close(None)
             .await
@@ -208,28 +832,45 @@ impl WebSocketConnection {
         &mut self,
         auth: String,
         query: &str,
-    ) -> Result<Option<String>, Error> {
+        request_id: &Option<String>,
+    ) -> Result<(Option<String>, u64), Error> {
         let project: Project = get_project(&self.deployment).await?;
         let ((unit_times, unit_overflow), jid) = project.compute_query_method(query)?;
 
         let (inactive, channel_id) = match &mut self.query_type {
             QueryType::CloseAgreement => {
                 if COMMAND.auth() {
-                    let deployment_id = verify_auth_ws(&auth)
+                    let deployment_id = verify_auth(&auth, unit_times)
                         .await
                         .map_err(|e| Error::Jsonrpc(jid, Arc::new(e)))?;
                     if deployment_id != self.deployment {
                         return Err(Error::Jsonrpc(jid, Arc::new(Error::AuthVerify(1004))));
                     }
+                    self.auth = auth;
                 }
 
-                return Ok(None);
+                return Ok((None, unit_times));
             }
             QueryType::PAYG(ref mut start, ref mut end) => {
-                let (inactive, channel_id, new_start, new_end) =
-                    Self::before_query_payg_check(auth, project, unit_times, unit_overflow)
-                        .await
-                        .map_err(|e| Error::Jsonrpc(jid, Arc::new(e)))?;
+                // limit the sockets of channel when first query
+                if self.channel_owner.is_none() {
+                    let channel = MultipleQueryState::from_bs64(auth.clone())
+                        .map_err(|e| Error::Jsonrpc(jid, Arc::new(e)))?
+                        .channel_id;
+                    let owner = WsOwner::Channel(format!("{:#X}", channel));
+                    acquire_owners(&[owner.clone()]).await?;
+                    self.channel_owner = Some(owner);
+                }
+
+                let (inactive, channel_id, new_start, new_end) = Self::before_query_payg_check(
+                    auth,
+                    project,
+                    unit_times,
+                    unit_overflow,
+                    request_id,
+                )
+                .await
+                .map_err(|e| Error::Jsonrpc(jid, Arc::new(e)))?;
 
                 if inactive.is_none() {
                     *start = new_start;
@@ -248,12 +889,12 @@ impl WebSocketConnection {
                     }
                 }
 
-                return Ok(None);
+                return Ok((None, unit_times));
             }
         };
 
         self.order_id = channel_id;
-        Ok(inactive)
+        Ok((inactive, unit_times))
     }
 
     async fn before_query_payg_check(
@@ -261,6 +902,7 @@ impl WebSocketConnection {
         _project: Project,
         unit_times: u64,
         _unit_overflow: u64,
+        request_id: &Option<String>,
     ) -> Result<(Option<String>, U256, U256, U256), Error> {
         let raw_state = MultipleQueryState::from_bs64(auth)?;
         let start = raw_state.start;
@@ -280,7 +922,7 @@ impl WebSocketConnection {
         }))
         .map_err(|_| Error::WebSocket(1305))?;
 
-        let cache_key = format!("{}-ws", keyname);
+        let cache_key = ws_cache_key(&keyname, request_id);
 
         let mut conn = redis();
         let _: () = redis::cmd("SETEX")
@@ -294,12 +936,15 @@ impl WebSocketConnection {
         Ok((None, channel_id, start, end))
     }
 
-    /// FIXME: when request with unit compute send to proxy,
-    /// proxy will store the tmp state cache, and when has response
-    /// will use it, if not fetch the tmp state cache,
-    /// will default use unit times = 1
-    async fn fetch_state(keyname: &str) -> Result<(String, StateCache), Error> {
-        let cache_key = format!("{}-ws", keyname);
+    /// when request with unit compute send to proxy,
+    /// proxy will store the tmp state cache of the request id, and when has response
+    /// will use it, if not fetch the tmp state cache (e.g. subscription notification),
+    /// will generate the state with the units of message
+    async fn fetch_state(
+        keyname: &str,
+        request_id: &Option<String>,
+    ) -> Result<(String, StateCache), Error> {
+        let cache_key = ws_cache_key(keyname, request_id);
 
         let mut conn = redis();
         let value: String = redis::cmd("GET")
@@ -321,7 +966,11 @@ impl WebSocketConnection {
         Ok((state_str, state_cache))
     }
 
-    async fn post_query_sync(&mut self) -> Result<(String, Option<(i64, i64)>), Error> {
+    async fn post_query_sync(
+        &mut self,
+        unit_times: u64,
+        request_id: Option<String>,
+    ) -> Result<(String, Option<(i64, i64)>), Error> {
         match self.query_type {
             QueryType::CloseAgreement => Ok(("".to_owned(), None)),
             QueryType::Whitelist => Ok(("".to_owned(), None)),
@@ -329,12 +978,10 @@ impl WebSocketConnection {
                 let keyname = channel_id_to_keyname(self.order_id);
 
                 let (state_str, state_cache) = if let Ok((state_str, state_cache)) =
-                    Self::fetch_state(&keyname).await
+                    Self::fetch_state(&keyname, &request_id).await
                 {
                     (state_str, state_cache)
                 } else {
-                    let unit_times = 1;
-
                     // fetch state cache
                     let (mut state_cache, _) = fetch_channel_cache(self.order_id).await?;
 
@@ -353,7 +1000,7 @@ impl WebSocketConnection {
                     .await?;
                     drop(account);
 
-                    // update state cache. default unit is 1
+                    // update state cache with the units of message
                     state_cache.spent = state_cache.spent + state_cache.price * unit_times;
 
                     (state.to_bs64(), state_cache)
@@ -396,31 +1043,69 @@ impl WebSocketConnection {
     }
 }
 
+/// the tmp state cache key of request
+fn ws_cache_key(keyname: &str, request_id: &Option<String>) -> String {
+    match request_id {
+        Some(id) => format!("{}-ws-{}", keyname, id),
+        None => format!("{}-ws", keyname),
+    }
+}
+
+/// the first message of jsonrpc batch, or the single message
+fn first_message(v: &Value) -> &Value {
+    match v {
+        Value::Array(list) => list.first().unwrap_or(&Value::Null),
+        _ => v,
+    }
+}
+
+fn message_id(v: &Value) -> Option<String> {
+    first_message(v).get("id").and_then(value_key)
+}
+
+fn value_key(v: &Value) -> Option<String> {
+    match v {
+        Value::Null => None,
+        Value::String(s) => Some(s.clone()),
+        _ => Some(v.to_string()),
+    }
+}
+
 enum SocketMessage {
     Client(Message),
     Remote(TMessage),
+    RemoteClosed,
+    Reconnected(Result<SocketConnection, Error>),
+    Tick,
 }
 
 pub async fn handle_websocket(
-    remote_socket: SocketConnection,
+    remote: RemoteSocket,
     client_socket: WebSocket,
     deployment: String,
     query_type: QueryType,
     no_sig: bool,
+    owners: Vec<WsOwner>,
 ) {
     debug!("WebSocket connected for deployment: {}", deployment);
-    let mut ws_connection = WebSocketConnection::new(
-        remote_socket,
-        client_socket,
-        query_type,
-        &deployment,
-        no_sig,
-    );
+    let mut ws_connection =
+        WebSocketConnection::new(remote, client_socket, query_type, &deployment, no_sig);
 
+    if let Err(e) = acquire_owners(&owners).await {
+        let _ = ws_connection.close_all(Some(e)).await;
+        return;
+    }
+    ws_connection.owners = owners;
+    update_metrics_ws(deployment.clone(), true).await;
+
+    let mut ticker = interval(Duration::from_secs(WS_PING_TIME));
+    ticker.tick().await;
     loop {
         let res = select! {
             v = async { ws_connection.client_socket.recv().await.and_then(|v| v.ok()).map(SocketMessage::Client) } => v,
-            v = async { ws_connection.remote_socket.next().await.and_then(|v| v.ok()).map(SocketMessage::Remote) } => v,
+            v = async { Some(ws_connection.remote_socket.next().await.and_then(|v| v.ok()).map(SocketMessage::Remote).unwrap_or(SocketMessage::RemoteClosed)) }, if ws_connection.reconnecting.is_none() => v,
+            v = async { reconnected(&mut ws_connection.reconnecting).await } => Some(v),
+            _ = ticker.tick() => Some(SocketMessage::Tick),
         };
 
         match res {
@@ -430,10 +1115,39 @@ pub async fn handle_websocket(
             Some(SocketMessage::Remote(msg)) => {
                 let _ = handle_remote_socket_message(&mut ws_connection, msg).await;
             }
+            Some(SocketMessage::RemoteClosed) => {
+                if !ws_connection.on_remote_closed().await {
+                    break;
+                }
+            }
+            Some(SocketMessage::Reconnected(res)) => {
+                if !ws_connection.on_reconnected(res).await {
+                    break;
+                }
+            }
+            Some(SocketMessage::Tick) => {
+                if let Some(e) = ws_connection.check_alive() {
+                    debug!("WebSocket closing for deployment: {} {:?}", deployment, e);
+                    let _ = ws_connection.close_all(Some(e)).await;
+                    break;
+                }
+                if ws_connection.ping().await.is_err() {
+                    let _ = ws_connection.close_all(None).await;
+                    break;
+                }
+            }
             None => break,
         }
     }
 
+    if let Some(task) = ws_connection.reconnecting.take() {
+        task.abort();
+    }
+    release_owners(&ws_connection.owners).await;
+    if let Some(owner) = ws_connection.channel_owner.take() {
+        release_owners(&[owner]).await;
+    }
+    update_metrics_ws(deployment.clone(), false).await;
     debug!("WebSocket closed for deployment: {}", deployment);
 }
 
@@ -444,15 +1158,21 @@ async fn handle_client_socket_message(
     match msg {
         Message::Text(text) => {
             debug!("Received text message from client");
-            if let Err(e) = ws_connection.receive_text_msg(&text).await {
-                ws_connection.send_error_msg(e).await;
-            }
+            receive_client_text(ws_connection, &text).await?;
         }
-        Message::Binary(_) => {
+        Message::Binary(data) => {
             debug!("Receive binary message to client");
-            ws_connection
-                .close_all(Some(Error::WebSocket(1310)))
-                .await?
+            // graphql clients maybe send the json text as binary frame
+            match String::from_utf8(data) {
+                Ok(text) if ws_connection.protocol.is_graphql() => {
+                    receive_client_text(ws_connection, &text).await?;
+                }
+                _ => {
+                    ws_connection
+                        .close_all(Some(Error::WebSocket(1310)))
+                        .await?
+                }
+            }
         }
         Message::Close(_) => {
             debug!("Client closed the WebSocket");
@@ -460,6 +1180,9 @@ async fn handle_client_socket_message(
         }
         Message::Ping(data) => {
             debug!("Received PING message from client");
+            if ws_connection.reconnecting.is_some() {
+                return Ok(());
+            }
             ws_connection
                 .remote_socket
                 .send(TMessage::Ping(data))
@@ -468,6 +1191,10 @@ async fn handle_client_socket_message(
         }
         Message::Pong(data) => {
             debug!("Received PONG message from client");
+            ws_connection.client_pong_at = Instant::now();
+            if data == WS_PING_PAYLOAD || ws_connection.reconnecting.is_some() {
+                return Ok(());
+            }
             ws_connection
                 .remote_socket
                 .send(TMessage::Pong(data))
@@ -479,6 +1206,21 @@ async fn handle_client_socket_message(
     Ok(())
 }
 
+/// handle the client query, close the sockets when cannot continue
+async fn receive_client_text(
+    ws_connection: &mut WebSocketConnection,
+    text: &str,
+) -> Result<(), Error> {
+    ws_connection.active_at = Instant::now();
+    if let Err(e) = ws_connection.receive_text_msg(text).await {
+        ws_connection.send_error_msg(&e).await;
+        if is_closing_error(&e) {
+            ws_connection.close_all(Some(e)).await?;
+        }
+    }
+    Ok(())
+}
+
 async fn handle_remote_socket_message(
     ws_connection: &mut WebSocketConnection,
     msg: TMessage,
@@ -486,6 +1228,16 @@ async fn handle_remote_socket_message(
     match msg {
         TMessage::Text(text) => {
             debug!("Received text response from remote");
+            ws_connection.active_at = Instant::now();
+            if let Err(e) = ws_connection.send_text_msg(text).await {
+                debug!("send message to client error: {:?}", e);
+                ws_connection.close_all(Some(e)).await?;
+            }
+        }
+        TMessage::Binary(data) if ws_connection.protocol.is_graphql() => {
+            debug!("Received binary response from remote");
+            ws_connection.active_at = Instant::now();
+            let text = String::from_utf8(data).map_err(|_| Error::WebSocket(1310))?;
             if let Err(e) = ws_connection.send_text_msg(text).await {
                 debug!("send message to client error: {:?}", e);
                 ws_connection.close_all(Some(e)).await?;
@@ -507,6 +1259,10 @@ async fn handle_remote_socket_message(
         }
         TMessage::Pong(data) => {
             debug!("Received PONG message from remote");
+            ws_connection.remote_pong_at = Instant::now();
+            if data == WS_PING_PAYLOAD {
+                return Ok(());
+            }
             ws_connection
                 .client_socket
                 .send(Message::Pong(data))
@@ -514,24 +1270,103 @@ async fn handle_remote_socket_message(
                 .map_err(|_| Error::WebSocket(1313))?;
         }
         TMessage::Close(_) => {
+            // the remote stream will end, and reconnect or close the client
             debug!("Remote closed the WebSocket");
-            ws_connection
-                .close_client(Some(Error::WebSocket(1308)))
-                .await?;
         }
     }
 
     Ok(())
 }
 
+/// wait the reconnect task, pending if not reconnecting
+async fn reconnected(
+    task: &mut Option<JoinHandle<Result<SocketConnection, Error>>>,
+) -> SocketMessage {
+    match task {
+        Some(task) => {
+            SocketMessage::Reconnected(task.await.unwrap_or_else(|_| Err(Error::WebSocket(1309))))
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// connect to the remote with backoff
+async fn reconnect_remote(
+    endpoint: String,
+    protocol: WsProtocol,
+) -> Result<SocketConnection, Error> {
+    let mut backoff = 1;
+    for _ in 0..WS_RECONNECT_TIMES {
+        match connect_to_project_ws(endpoint.clone(), protocol).await {
+            Ok(remote) => return Ok(remote.socket),
+            Err(_) => {
+                sleep(Duration::from_secs(backoff)).await;
+                backoff = (backoff * 2).min(WS_RECONNECT_MAX_BACKOFF);
+            }
+        }
+    }
+    Err(Error::WebSocket(1309))
+}
+
+/// the auth expired or the quota exhausted, the socket cannot continue
+fn is_closing_error(error: &Error) -> bool {
+    match error {
+        Error::Jsonrpc(_, e) => is_closing_error(e),
+        Error::AuthExpired(..) | Error::AuthVerify(..) | Error::DailyLimit(..) => true,
+        Error::WebSocket(1320) => true,
+        _ => false,
+    }
+}
+
 // Asynchronously connect to a remote WebSocket endpoint
-pub async fn connect_to_project_ws(endpoint: String) -> Result<SocketConnection, Error> {
+pub async fn connect_to_project_ws(
+    endpoint: String,
+    protocol: WsProtocol,
+) -> Result<RemoteSocket, Error> {
     debug!("Connecting to the server: {}", endpoint);
-    let (socket, _) = tokio_tungstenite::connect_async(endpoint)
+    // the request with random handshake key
+    let mut request = endpoint
+        .as_str()
+        .into_client_request()
+        .map_err(|_| Error::WebSocket(1300))?;
+    if let Some(subprotocol) = protocol.subprotocol() {
+        request.headers_mut().insert(
+            header::SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static(subprotocol),
+        );
+    }
+
+    let (socket, _) = tokio_tungstenite::connect_async(request)
         .await
         .map_err(|_| Error::WebSocket(1308))?;
 
-    Ok(socket)
+    Ok(RemoteSocket {
+        endpoint,
+        protocol,
+        socket,
+    })
+}
+
+/// the standard close code of the error, the error code is in reason
+fn close_code(error: &Error) -> u16 {
+    match error {
+        Error::Jsonrpc(_, e) => close_code(e),
+        // normal closure
+        Error::WebSocket(1317) => 1000,
+        // going away
+        Error::WebSocket(1318) | Error::WebSocket(1319) => 1001,
+        // unsupported data
+        Error::WebSocket(1310) => 1003,
+        // policy violation
+        Error::WebSocket(1320)
+        | Error::AuthExpired(..)
+        | Error::AuthVerify(..)
+        | Error::DailyLimit(..)
+        | Error::RateLimit(..)
+        | Error::Permission(..) => 1008,
+        // internal error
+        _ => 1011,
+    }
 }
 
 async fn close_socket(socket: &mut WebSocket, error: Option<Error>) -> Result<(), Error> {
@@ -539,8 +1374,8 @@ async fn close_socket(socket: &mut WebSocket, error: Option<Error>) -> Result<()
     let (_, code, reason) = error.to_status_message();
     socket
         .send(Message::Close(Some(CloseFrame {
-            code: *code as u16,
-            reason: reason.into(),
+            code: close_code(&error),
+            reason: format!("{}: {}", code, reason).into(),
         })))
         .await
         .map_err(|_| Error::WebSocket(1311))?;
@@ -549,8 +1384,8 @@ async fn close_socket(socket: &mut WebSocket, error: Option<Error>) -> Result<()
 
 pub async fn validate_project(deployment: &str, ep_name: &str) -> Result<String, Error> {
     let project: crate::project::Project = get_project(&deployment).await?;
-    if !project.is_rpc_project() {
-        // only rpc project support websocket
+    if !project.is_rpc_project() && !project.is_subgraph_project() {
+        // only rpc & subgraph project support websocket
         return Err(Error::WebSocket(1300));
     }
 
@@ -561,3 +1396,112 @@ pub async fn validate_project(deployment: &str, ep_name: &str) -> Result<String,
         Ok(endpoint.endpoint.clone())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::project::{Endpoint, ProjectType, RpcMainfest, PROJECTS};
+    use axum::{extract::WebSocketUpgrade, routing::get, Router};
+    use ethers::types::Address;
+    use tokio::net::TcpListener;
+
+    /// start a remote socket which drop the connection when received the request
+    async fn mock_remote_drop() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let _ = socket.next().await;
+            drop(socket);
+        });
+        format!("ws://{}", addr)
+    }
+
+    async fn mock_project(deployment: &str, endpoint: String) {
+        let mut endpoints = HashMap::new();
+        endpoints.insert(
+            "default".to_owned(),
+            Endpoint {
+                endpoint,
+                is_internal: false,
+                is_ws: true,
+                rpc_family: vec![],
+            },
+        );
+        let project = Project {
+            id: deployment.to_owned(),
+            ptype: ProjectType::RpcEvm(RpcMainfest::default()),
+            endpoints,
+            rate_limit: None,
+            db_size: None,
+            payg_price: U256::zero(),
+            payg_token: Address::zero(),
+            payg_expiration: 0,
+            payg_overflow: 0,
+            payg_output_price: U256::zero(),
+            payg_model_prices: HashMap::new(),
+        };
+        PROJECTS.lock().await.insert(deployment.to_owned(), project);
+    }
+
+    /// start the proxy socket to the remote endpoint, return the address
+    async fn mock_proxy(deployment: &'static str, endpoint: String) -> String {
+        let app = Router::new().route(
+            "/",
+            get(move |ws: WebSocketUpgrade| async move {
+                ws.on_upgrade(move |socket| async move {
+                    let remote = connect_to_project_ws(endpoint, WsProtocol::Jsonrpc)
+                        .await
+                        .unwrap();
+                    handle_websocket(
+                        remote,
+                        socket,
+                        deployment.to_owned(),
+                        QueryType::Whitelist,
+                        true,
+                        vec![],
+                    )
+                    .await
+                })
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("ws://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn remote_dropped_mid_request() {
+        let deployment = "QmWsRemoteDropped";
+        let remote = mock_remote_drop().await;
+        mock_project(deployment, remote.clone()).await;
+        let proxy = mock_proxy(deployment, remote).await;
+
+        let (mut client, _) = tokio_tungstenite::connect_async(proxy).await.unwrap();
+        let request = json!({
+            "body": json!({"jsonrpc": "2.0", "id": 7, "method": "eth_blockNumber", "params": []}).to_string(),
+            "auth": "",
+        });
+        client
+            .send(TMessage::Text(request.to_string()))
+            .await
+            .unwrap();
+
+        // the request in flight response the error, not waiting forever
+        let response = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                match client.next().await {
+                    Some(Ok(TMessage::Text(text))) => break from_str::<Value>(&text).unwrap(),
+                    Some(Ok(_)) => continue,
+                    _ => panic!("client closed without the response"),
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(response["id"], 7);
+        assert_eq!(response["error"]["code"], 1321);
+    }
+}
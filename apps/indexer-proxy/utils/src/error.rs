@@ -25,7 +25,7 @@ use serde_json::{json, Value};
 use std::sync::Arc;
 
 /// App error type.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Error {
     AuthCreate(i32),
     AuthVerify(i32),
@@ -52,6 +52,7 @@ pub enum Error {
     RateLimit(i32),
     Expired(i32),
     Overflow(i32),
+    Lagging(i32),
 
     Serialize(i32),
 
@@ -113,6 +114,7 @@ impl Error {
             Error::RateLimit(c) => (StatusCode::BAD_REQUEST, c, "Exceed rate limit"),
             Error::Expired(c) => (StatusCode::BAD_REQUEST, c, "Service expired"),
             Error::Overflow(c) => (StatusCode::BAD_REQUEST, c, "Query overflow"),
+            Error::Lagging(c) => (StatusCode::SERVICE_UNAVAILABLE, c, "Project lagging"),
             Error::Serialize(c) => (StatusCode::BAD_REQUEST, c, "Invalid serialize"),
             Error::WebSocket(c) => (StatusCode::BAD_REQUEST, c, "WebSocket error"),
             Error::AiTokenizer(c) => (StatusCode::INTERNAL_SERVER_ERROR, c, "AI Tokenizer missing"),
@@ -24,6 +24,18 @@ pub const ROOT_GROUP_ID: u64 = 12408845626691334533;
 /// Root name for projects
 pub const ROOT_NAME: &str = "SubQuery";
 
+/// The p2p protocol version, 1 is the legacy bincode/json event, 2 is the envelope.
+pub const P2P_PROTOCOL_VERSION: u16 = 2;
+
+/// The first byte of envelope, the legacy bincode event starts with variant index (little endian).
+const ENVELOPE_MAGIC: u8 = 0xFF;
+
+/// magic(1) + protocol version(2) + type id(2) + payload length(4)
+const ENVELOPE_HEADER_LEN: usize = 9;
+
+/// The last type id known by this version, the newer types will be skipped.
+const LAST_TYPE_ID: u16 = 19;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct JoinData(pub Vec<String>);
 
@@ -38,11 +50,11 @@ pub enum Event {
     ProjectJoinRes,
     /// Project leave
     ProjectLeave,
-    /// Request the project poi,
-    /// params: project, poi block hash
+    /// Request the project metadata, or the poi when has block height,
+    /// params: project, poi block height
     ProjectMetadata(String, Option<u64>),
-    /// Response project poi
-    /// params: project poi
+    /// Response project metadata or signed poi
+    /// params: project metadata or poi
     ProjectMetadataRes(String),
     /// Report indexer services status,
     /// params: project or all
@@ -56,23 +68,23 @@ pub enum Event {
     /// Response the channel open,
     /// params: uid, open state
     PaygOpenRes(u64, String),
-    /// Query data the by channel,
-    /// params: uid, query, ep_name, state
+    /// Query data the by channel, the project is the deployment of channel,
+    /// params: uid, query, ep_name, state (multiple state base64 or single state json base64)
     PaygQuery(u64, String, Option<String>, String),
     /// Response the channel query,
-    /// params: uid, data with state
+    /// params: uid, Json(result, signature, state)
     PaygQueryRes(u64, String),
     /// Query the close agreement limit,
-    /// params: uid, agreement id
+    /// params: uid, auth token of agreement
     CloseAgreementLimit(u64, String),
     /// Response the close agreement limit
     /// params: uid, agreement info
     CloseAgreementLimitRes(u64, String),
     /// Query data by close agreement,
-    /// params: uid, agreement, query, ep_name
+    /// params: uid, auth token of agreement, query, ep_name
     CloseAgreementQuery(u64, String, String, Option<String>),
     /// Response the close agreement query,
-    /// params: uid, data
+    /// params: uid, Json(result, signature)
     CloseAgreementQueryRes(u64, String),
     /// Report project query log to whitelist use root group id, every 30min, time is ms.
     /// params: indexer, [
@@ -91,16 +103,109 @@ pub enum Event {
     MetricsPaygConflict(String, String, String, i32, i64, i64),
     /// above
     MetricsQueryCount2(String, Vec<(String, u64, u64, Vec<(u64, u64, u64)>)>),
+    /// The event type from newer peers, only decoded from envelope and skipped,
+    /// params: type id
+    #[serde(skip)]
+    Unknown(u16),
 }
 
 impl Event {
+    /// Stable type id of the event in envelope, never reuse or reorder,
+    /// change the params of event need a new type.
+    pub fn type_id(&self) -> u16 {
+        match self {
+            Event::IndexerHealthy(..) => 1,
+            Event::ProjectJoin(..) => 2,
+            Event::ProjectJoinRes => 3,
+            Event::ProjectLeave => 4,
+            Event::ProjectMetadata(..) => 5,
+            Event::ProjectMetadataRes(..) => 6,
+            Event::PaygPrice(..) => 7,
+            Event::PaygPriceRes(..) => 8,
+            Event::PaygOpen(..) => 9,
+            Event::PaygOpenRes(..) => 10,
+            Event::PaygQuery(..) => 11,
+            Event::PaygQueryRes(..) => 12,
+            Event::CloseAgreementLimit(..) => 13,
+            Event::CloseAgreementLimitRes(..) => 14,
+            Event::CloseAgreementQuery(..) => 15,
+            Event::CloseAgreementQueryRes(..) => 16,
+            Event::MetricsQueryCount(..) => 17,
+            Event::MetricsPaygConflict(..) => 18,
+            Event::MetricsQueryCount2(..) => 19,
+            Event::Unknown(type_id) => *type_id,
+        }
+    }
+
+    /// The name of event type, used in metrics and logs.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::IndexerHealthy(..) => "IndexerHealthy",
+            Event::ProjectJoin(..) => "ProjectJoin",
+            Event::ProjectJoinRes => "ProjectJoinRes",
+            Event::ProjectLeave => "ProjectLeave",
+            Event::ProjectMetadata(..) => "ProjectMetadata",
+            Event::ProjectMetadataRes(..) => "ProjectMetadataRes",
+            Event::PaygPrice(..) => "PaygPrice",
+            Event::PaygPriceRes(..) => "PaygPriceRes",
+            Event::PaygOpen(..) => "PaygOpen",
+            Event::PaygOpenRes(..) => "PaygOpenRes",
+            Event::PaygQuery(..) => "PaygQuery",
+            Event::PaygQueryRes(..) => "PaygQueryRes",
+            Event::CloseAgreementLimit(..) => "CloseAgreementLimit",
+            Event::CloseAgreementLimitRes(..) => "CloseAgreementLimitRes",
+            Event::CloseAgreementQuery(..) => "CloseAgreementQuery",
+            Event::CloseAgreementQueryRes(..) => "CloseAgreementQueryRes",
+            Event::MetricsQueryCount(..) => "MetricsQueryCount",
+            Event::MetricsPaygConflict(..) => "MetricsPaygConflict",
+            Event::MetricsQueryCount2(..) => "MetricsQueryCount2",
+            Event::Unknown(..) => "Unknown",
+        }
+    }
+
+    /// Encode to envelope:
+    /// magic(1) + protocol version(2) + type id(2) + payload length(4) + payload(json)
     pub fn to_bytes(&self) -> Vec<u8> {
-        bincode::serialize(self).unwrap_or(vec![])
+        let payload = serde_json::to_vec(self).unwrap_or(vec![]);
+        let mut bytes = Vec::with_capacity(ENVELOPE_HEADER_LEN + payload.len());
+        bytes.push(ENVELOPE_MAGIC);
+        bytes.extend(P2P_PROTOCOL_VERSION.to_be_bytes());
+        bytes.extend(self.type_id().to_be_bytes());
+        bytes.extend((payload.len() as u32).to_be_bytes());
+        bytes.extend(payload);
+        bytes
     }
 
+    /// Decode the envelope, or the legacy bincode event (version 1).
     pub fn from_bytes(data: &[u8]) -> std::io::Result<Self> {
-        bincode::deserialize(data).map_err(|_| {
-            std::io::Error::new(std::io::ErrorKind::Other, "P2P Event deserialize failure")
-        })
+        if data.first() != Some(&ENVELOPE_MAGIC) {
+            return bincode::deserialize(data)
+                .map_err(|_| p2p_error("P2P Event deserialize failure"));
+        }
+        if data.len() < ENVELOPE_HEADER_LEN {
+            return Err(p2p_error("P2P Event envelope too short"));
+        }
+
+        // the newer version is ok, the known types keep the same payload,
+        // and the unknown types are skipped without decoding the payload
+        let type_id = u16::from_be_bytes([data[3], data[4]]);
+        if type_id == 0 || type_id > LAST_TYPE_ID {
+            return Ok(Event::Unknown(type_id));
+        }
+        let len = u32::from_be_bytes([data[5], data[6], data[7], data[8]]) as usize;
+        let payload = data
+            .get(ENVELOPE_HEADER_LEN..ENVELOPE_HEADER_LEN + len)
+            .ok_or(p2p_error("P2P Event envelope payload too short"))?;
+
+        let event: Event = serde_json::from_slice(payload)
+            .map_err(|_| p2p_error("P2P Event unknown type or invalid payload"))?;
+        if event.type_id() != type_id {
+            return Err(p2p_error("P2P Event type mismatch"));
+        }
+        Ok(event)
     }
 }
+
+fn p2p_error(msg: &'static str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Other, msg)
+}
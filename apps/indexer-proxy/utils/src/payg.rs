@@ -624,6 +624,47 @@ pub async fn price_sign(
     Ok(sign)
 }
 
+/// the price with a domain tag, used by the prices other than the unit price,
+/// e.g. `output`, `model:<name>:input`, `model:<name>:output`
+pub fn price_tag_recover(
+    tag: &str,
+    price: U256,
+    token: Address,
+    expired: i64,
+    sign: Signature,
+) -> Result<Address, Error> {
+    let payload = encode(&[
+        tag.to_owned().into_token(),
+        price.into_token(),
+        token.into_token(),
+        expired.into_token(),
+    ]);
+    let hash = keccak256(payload);
+    let signer = sign.recover(&hash[..])?;
+    Ok(signer)
+}
+
+pub async fn price_tag_sign(
+    tag: &str,
+    price: U256,
+    token: Address,
+    expired: i64,
+    key: &impl Signer,
+) -> Result<Signature, Error> {
+    let payload = encode(&[
+        tag.to_owned().into_token(),
+        price.into_token(),
+        token.into_token(),
+        expired.into_token(),
+    ]);
+    let hash = keccak256(payload);
+    let sign = key
+        .sign_message(hash)
+        .await
+        .map_err(|_| Error::InvalidSignature(1041))?;
+    Ok(sign)
+}
+
 #[derive(Copy, Clone, Debug)]
 pub enum MultipleQueryStateActive {
     Active,